@@ -0,0 +1,310 @@
+use crate::cst::*;
+use crate::device::*;
+use crate::helpers::*;
+
+pub const MAX_ARP_NOTES: usize = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArpMode {
+    #[default]
+    Up,
+    Down,
+    UpDown,
+    Random,
+    AsPlayed,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct HeldNote {
+    note: Note,
+    velocity: u32,
+    // Whether the key is physically down, as opposed to just latched
+    down: bool,
+}
+
+// Sits in front of a SynthDevice, turning held notes into a stream of note_on/note_off
+// calls on the wrapped device. Key presses take effect at the start of the next run.
+#[derive(Clone)]
+pub struct Arpeggiator<D, const NUM_PARAMS: usize>
+where
+    D: SynthDevice<NUM_PARAMS>,
+{
+    pub device: D,
+
+    mode: ArpMode,
+    octaves: u8,
+    tempo: u32,
+    steps_per_beat: u32,
+    gate: Sample,
+    latch: bool,
+
+    held: [HeldNote; MAX_ARP_NOTES],
+    held_count: usize,
+
+    step: usize,
+    step_samples: usize,
+    samples_to_step: usize,
+    samples_to_off: usize,
+    playing: Option<Note>,
+    rng: Rng,
+}
+
+impl<D, const NUM_PARAMS: usize> Arpeggiator<D, NUM_PARAMS>
+where
+    D: SynthDevice<NUM_PARAMS>,
+{
+    pub fn new(device: D) -> Self {
+        let mut arp = Arpeggiator {
+            device,
+            mode: ArpMode::Up,
+            octaves: 1,
+            tempo: 120,
+            steps_per_beat: 4,
+            gate: sf!(0.5),
+            latch: false,
+            held: [Default::default(); MAX_ARP_NOTES],
+            held_count: 0,
+            step: 0,
+            step_samples: 0,
+            samples_to_step: 0,
+            samples_to_off: 0,
+            playing: None,
+            rng: Default::default(),
+        };
+        arp.update_step_samples();
+        arp
+    }
+
+    pub fn note_on(&mut self, note: Note, velocity: u32) {
+        if self.latch && !self.held[..self.held_count].iter().any(|h| h.down) {
+            // A fresh chord replaces whatever was latched
+            self.held_count = 0;
+        }
+        if self.held_count == 0 {
+            self.step = 0;
+            self.samples_to_step = 0;
+        }
+        if let Some(h) = self.held[..self.held_count]
+            .iter_mut()
+            .find(|h| h.note == note)
+        {
+            h.velocity = velocity;
+            h.down = true;
+        } else if self.held_count < MAX_ARP_NOTES {
+            self.held[self.held_count] = HeldNote {
+                note,
+                velocity,
+                down: true,
+            };
+            self.held_count += 1;
+        }
+    }
+
+    pub fn note_off(&mut self, note: Note) {
+        if let Some(h) = self.held[..self.held_count]
+            .iter_mut()
+            .find(|h| h.note == note)
+        {
+            h.down = false;
+        }
+        if !self.latch {
+            self.remove_released();
+        }
+    }
+
+    pub fn all_notes_off(&mut self) {
+        self.held_count = 0;
+        self.playing = None;
+        self.device.all_notes_off();
+    }
+
+    fn remove_released(&mut self) {
+        let mut j = 0;
+        for i in 0..self.held_count {
+            if self.held[i].down {
+                self.held[j] = self.held[i];
+                j += 1;
+            }
+        }
+        self.held_count = j;
+    }
+
+    fn update_step_samples(&mut self) {
+        self.step_samples =
+            ((sample_rate() * 60) / (self.tempo * self.steps_per_beat)).max(1) as usize;
+    }
+
+    // Works out which held note (and velocity) plays on the current step
+    fn current_note(&mut self) -> (Note, u32) {
+        let count = self.held_count;
+        let len = count * self.octaves as usize;
+
+        let index = match self.mode {
+            ArpMode::Up | ArpMode::AsPlayed => self.step % len,
+            ArpMode::Down => len - 1 - (self.step % len),
+            ArpMode::UpDown => {
+                if len < 2 {
+                    0
+                } else {
+                    let i = self.step % (len * 2 - 2);
+                    if i < len {
+                        i
+                    } else {
+                        len * 2 - 2 - i
+                    }
+                }
+            }
+            ArpMode::Random => self.rng.next_u32() as usize % len,
+        };
+
+        let held = if self.mode == ArpMode::AsPlayed {
+            self.held[index % count]
+        } else {
+            // Sort the held notes by pitch, there's only ever a handful of them
+            let mut sorted = self.held;
+            for i in 1..count {
+                let mut j = i;
+                while j > 0 && *sorted[j - 1].note > *sorted[j].note {
+                    sorted.swap(j - 1, j);
+                    j -= 1;
+                }
+            }
+            sorted[index % count]
+        };
+        // Octaves that would go past the top midi note are played an octave lower instead,
+        // rather than all piling up on the highest note the frequency conversion allows
+        let mut octave = (index / count) as i32;
+        while octave > 0 && *held.note + h!(12 * octave) > h!(127) {
+            octave -= 1;
+        }
+        ((*held.note + h!(12 * octave)).into(), held.velocity)
+    }
+
+    // Schedule the arpeggio for the next samples samples onto the wrapped device
+    fn schedule(&mut self, samples: usize) {
+        let mut position = 0;
+        loop {
+            let mut next = self.samples_to_step;
+            if self.playing.is_some() {
+                next = next.min(self.samples_to_off);
+            }
+            if position + next >= samples {
+                let left = samples - position;
+                self.samples_to_step = self.samples_to_step.saturating_sub(left);
+                self.samples_to_off = self.samples_to_off.saturating_sub(left);
+                return;
+            }
+            position += next;
+            self.samples_to_step -= next;
+            self.samples_to_off = self.samples_to_off.saturating_sub(next);
+
+            if let Some(note) = self.playing {
+                if self.samples_to_off == 0 || self.samples_to_step == 0 {
+                    self.device.note_off(note, position);
+                    self.playing = None;
+                }
+            }
+            if self.samples_to_step == 0 {
+                self.samples_to_step = self.step_samples;
+                if self.held_count > 0 {
+                    let (note, velocity) = self.current_note();
+                    self.device.note_on(note, velocity, position);
+                    self.playing = Some(note);
+                    self.samples_to_off = ((self.step_samples as i64 * self.gate.to_bits() as i64)
+                        >> Sample::FRAC_BITS)
+                        .max(1) as usize;
+                    self.step = self.step.wrapping_add(1);
+                } else {
+                    self.step = 0;
+                }
+            }
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: ArpMode) {
+        self.mode = mode;
+    }
+    pub fn get_mode(&self) -> ArpMode {
+        self.mode
+    }
+    pub fn set_octaves(&mut self, octaves: u8) {
+        self.octaves = octaves.clamp(1, 4);
+    }
+    pub fn get_octaves(&self) -> u8 {
+        self.octaves
+    }
+    pub fn set_tempo(&mut self, bpm: u32) {
+        self.tempo = bpm.max(1);
+        self.update_step_samples();
+    }
+    pub fn get_tempo(&self) -> u32 {
+        self.tempo
+    }
+    pub fn set_steps_per_beat(&mut self, steps: u32) {
+        self.steps_per_beat = steps.max(1);
+        self.update_step_samples();
+    }
+    pub fn get_steps_per_beat(&self) -> u32 {
+        self.steps_per_beat
+    }
+    // Fraction of each step the note is held for
+    pub fn set_gate(&mut self, gate: Sample) {
+        self.gate = gate.clamp(s!(0), s!(1));
+    }
+    pub fn get_gate(&self) -> Sample {
+        self.gate
+    }
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        if !latch {
+            self.remove_released();
+        }
+    }
+    pub fn get_latch(&self) -> bool {
+        self.latch
+    }
+    pub fn seed(&mut self, seed: u32) {
+        self.rng = Rng::new(seed);
+    }
+}
+
+// The arpeggiator's place in the pattern goes with the device's state
+impl<D, const NUM_PARAMS: usize> Snapshot for Arpeggiator<D, NUM_PARAMS>
+where
+    D: SynthDevice<NUM_PARAMS> + Clone,
+{
+    type State = Self;
+    fn snapshot(&self) -> Self {
+        self.clone()
+    }
+    fn restore(&mut self, state: &Self) {
+        self.clone_from(state);
+    }
+}
+
+impl<D, const NUM_PARAMS: usize> Device<NUM_PARAMS> for Arpeggiator<D, NUM_PARAMS>
+where
+    D: SynthDevice<NUM_PARAMS>,
+{
+    const NAME: &'static str = D::NAME;
+    type Param = D::Param;
+    fn run(&mut self, song_position: usize, buffer: &mut [Sample]) -> Result<usize, DeviceError> {
+        self.schedule(buffer.len());
+        self.device.run(song_position, buffer)
+    }
+    fn set_param<T: Parameter>(&mut self, ty: Self::Param, value: T) {
+        self.device.set_param(ty, value);
+    }
+    fn get_param<T: Parameter>(&self, ty: Self::Param) -> T {
+        self.device.get_param(ty)
+    }
+    fn param_precision(ty: Self::Param) -> ParamPrecision {
+        D::param_precision(ty)
+    }
+    fn set_chunk(&mut self, chunk: [i32; NUM_PARAMS]) {
+        self.device.set_chunk(chunk);
+    }
+    fn get_chunk(&self) -> [i32; NUM_PARAMS] {
+        self.device.get_chunk()
+    }
+}
@@ -1,10 +1,56 @@
 // constants
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
 use pico_donk_proc_macro::{tables, types};
 
-pub const SAMPLE_RATE: u32 = 48000;
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+static SAMPLE_RATE: AtomicU32 = AtomicU32::new(DEFAULT_SAMPLE_RATE);
+static SAMPLE_RATE_LOCKED: AtomicBool = AtomicBool::new(false);
+
+#[inline]
+pub fn sample_rate() -> u32 {
+    SAMPLE_RATE.load(Ordering::Relaxed)
+}
+
+// Anything that turns times or frequencies into per-sample steps (envelopes, lfos,
+// oscillators, Song) does it when they're set and never looks again, so the rate can
+// only be set once at startup. Panics once it's been locked.
+pub fn set_sample_rate(rate: u32) {
+    assert!(
+        !SAMPLE_RATE_LOCKED.load(Ordering::Relaxed),
+        "set_sample_rate must be called before anything is built"
+    );
+    SAMPLE_RATE.store(rate.max(1), Ordering::Relaxed);
+}
+
+// Stops the rate changing from here on. Song and synth devices call this when they're
+// built, call it yourself before using anything else that reads sample_rate.
+pub fn lock_sample_rate() {
+    SAMPLE_RATE_LOCKED.store(true, Ordering::Relaxed);
+}
 
 types!();
+
+// The firmware picks the table sizes with the tables-small and tables-large features
+#[cfg(all(feature = "tables-small", feature = "tables-large"))]
+compile_error!("only one of the tables-small and tables-large features can be on");
+#[cfg(all(feature = "tables-small", not(feature = "tables-large")))]
+tables!(sin = 8, exp = 8, log2 = 8, tanh = 8, pow = 8, sinc = false);
+#[cfg(all(feature = "tables-large", not(feature = "tables-small")))]
+tables!(
+    sin = 12,
+    exp = 12,
+    log2 = 12,
+    tanh = 12,
+    pow = 12,
+    sinc = 12,
+    sinc_taps = 16
+);
+#[cfg(not(any(feature = "tables-small", feature = "tables-large")))]
 tables!();
 
 pub const DRUMS: &'static [u8] = include_bytes!("../dat/cw_amen08_165.raw");
 pub const LENGTH: usize = DRUMS.len() / 2;
+// The rate the song is written at, and the drums were recorded at
+pub const SONG_SAMPLE_RATE: u32 = 48000;
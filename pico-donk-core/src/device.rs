@@ -1,42 +1,183 @@
 use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
 
 use crate::cst::*;
 use crate::helpers::*;
+use crate::modulation::*;
+use crate::tuning::*;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceErrorKind {
+    // run was given less buffer than the device needs, e.g. for a fixed block size
+    BufferTooSmall { needed: usize, got: usize },
+    // Note events came in while the event queue was full, and this many were lost
+    EventOverflow { dropped: u32 },
+    // A parameter was out of range, by its place in the chunk
+    InvalidParameter { index: usize },
+    // Fixed point maths went out of range, see MathError
+    NumericOverflow,
+    // The device plays a sample that hasn't been loaded
+    SampleMissing,
+}
+
+impl fmt::Display for DeviceErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceErrorKind::BufferTooSmall { needed, got } => f.write_fmt(format_args!(
+                "buffer too small, needed {} samples but got {}",
+                needed, got
+            )),
+            DeviceErrorKind::EventOverflow { dropped } => f.write_fmt(format_args!(
+                "dropped {} events, its event queue was full",
+                dropped
+            )),
+            DeviceErrorKind::InvalidParameter { index } => {
+                f.write_fmt(format_args!("parameter {} is out of range", index))
+            }
+            DeviceErrorKind::NumericOverflow => f.write_str("numeric overflow"),
+            DeviceErrorKind::SampleMissing => f.write_str("sample missing"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct DeviceError {
     name: &'static str,
+    kind: DeviceErrorKind,
+    // Which voice it happened in, for errors from Voice::run
+    voice: Option<usize>,
 }
 
 impl DeviceError {
-    fn new<T: Device<A>, const A: usize>() -> DeviceError {
-        DeviceError { name: T::NAME }
+    pub fn new(name: &'static str, kind: DeviceErrorKind) -> DeviceError {
+        DeviceError {
+            name,
+            kind,
+            voice: None,
+        }
+    }
+
+    // For Voice::run, which doesn't know which device it's in, that gets filled in by in_voice
+    pub fn from_voice(kind: DeviceErrorKind) -> DeviceError {
+        DeviceError::new("", kind)
+    }
+
+    // Marks an error from a voice with the device and voice it came from
+    pub fn in_voice(self, name: &'static str, voice: usize) -> DeviceError {
+        DeviceError {
+            name,
+            voice: Some(voice),
+            ..self
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn kind(&self) -> DeviceErrorKind {
+        self.kind
+    }
+
+    pub fn voice(&self) -> Option<usize> {
+        self.voice
     }
 }
 
 impl fmt::Display for DeviceError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_fmt(format_args!("Device {} malfunctioned", self.name))
+        match self.voice {
+            Some(voice) => f.write_fmt(format_args!(
+                "Device {} voice {}: {}",
+                self.name, voice, self.kind
+            )),
+            None => f.write_fmt(format_args!("Device {}: {}", self.name, self.kind)),
+        }
     }
 }
 
 pub trait Device<const NUM_PARAMS: usize> {
     const NAME: &'static str;
-    type Param;
+    type Param: DeviceParameters;
+    // Renders buffer starting song_position samples into the song, and returns the position
+    // just after it, see position_to_seconds/position_to_beats
     fn run(&mut self, song_position: usize, buffer: &mut [Sample]) -> Result<usize, DeviceError>;
     fn set_param<T: Parameter>(&mut self, ty: Self::Param, value: T) -> ();
     fn get_param<T: Parameter>(&self, ty: Self::Param) -> T;
+    // How the parameter is kept in the chunk, see ParamPrecision
+    fn param_precision(ty: Self::Param) -> ParamPrecision;
     fn set_chunk(&mut self, chunk: [i32; NUM_PARAMS]) -> ();
     fn get_chunk(&self) -> [i32; NUM_PARAMS];
 }
 
+// Everything a device needs to carry on rendering from the same point: parameters, voice
+// phases, envelopes, slides and pending events. Restoring a snapshot and running again gives
+// the same output, so hosts can seek and rendering can be resumed from a saved point.
+// device!/synth_device! make one for every device, by cloning it.
+pub trait Snapshot {
+    type State;
+    fn snapshot(&self) -> Self::State;
+    fn restore(&mut self, state: &Self::State);
+}
+
+// What a device's parameter enum knows about its parameters, made by device!/synth_device!
+// or #[derive(DeviceParameters)], and used by the generated Device impls
+pub trait DeviceParameters: Copy {
+    const COUNT: usize;
+    // Where the parameter is in the chunk
+    fn index(self) -> usize;
+    // How the parameter is kept in the chunk, see ParamPrecision
+    fn precision(self) -> ParamPrecision;
+    // value converted to the parameter's own type, then to chunk bits
+    fn to_chunk<T: Parameter>(self, value: T) -> i32;
+    fn from_chunk<T: Parameter>(self, bits: i32) -> T;
+    // A Param converted to the parameter's own type, then to T
+    fn convert<T: Parameter>(self, value: Param) -> T;
+}
+
+// What a voice gets to see of its device's parameters, handed to it by the device
+// for each call rather than kept, so voices never hold on to the device
+#[derive(Clone, Copy)]
+pub struct ParamView<'a, P: DeviceParameters> {
+    chunk: &'a [i32],
+    _param: PhantomData<P>,
+}
+
+impl<'a, P: DeviceParameters> ParamView<'a, P> {
+    pub fn new(chunk: &'a [i32]) -> Self {
+        ParamView {
+            chunk,
+            _param: PhantomData,
+        }
+    }
+    // The parameter converted like Device::get_param
+    pub fn get<T: Parameter>(&self, ty: P) -> T {
+        ty.from_chunk(self.chunk[ty.index()])
+    }
+    // The parameter as its own type, e.g. EnvValue for an EnvValue parameter, which unlike
+    // get doesn't go through a Param on the way
+    pub fn value<T: ParamStorage>(&self, ty: P) -> T {
+        debug_assert!(T::PRECISION == ty.precision());
+        T::from_chunk(self.chunk[ty.index()])
+    }
+    // The parameter as it's kept in the chunk
+    pub fn bits(&self, ty: P) -> i32 {
+        self.chunk[ty.index()]
+    }
+}
+
 pub trait SynthDevice<const NUM_PARAMS: usize>
 where
     Self: Device<NUM_PARAMS>,
 {
-    const MAX_VOICES: usize = 8;
-    const MAX_EVENTS: usize = 8;
-    const MAX_ACTIVE_NOTES: usize = 4;
+    // Set per device with #[synth(voices = 16, events = 8, active_notes = 4)],
+    // which also sizes the device's arrays
+    const MAX_VOICES: usize;
+    const MAX_EVENTS: usize;
+    const MAX_ACTIVE_NOTES: usize;
     type Voice;
     fn all_notes_off(&mut self);
     fn note_on(&mut self, note: Note, velocity: u32, delta_samples: usize);
@@ -58,28 +199,107 @@ where
     fn set_rise(&mut self, n: Sample);
     fn set_slide(&mut self, n: SlideTime);
 
+    fn get_tuning(&self) -> &'static Tuning;
+    fn set_tuning(&mut self, n: &'static Tuning);
+
+    // Reseed every voice's random number generator, e.g. from Rng::from_song_position
+    fn seed(&mut self, seed: u32);
+
+    fn get_mod_wheel(&self) -> Sample;
+    fn set_mod_wheel(&mut self, n: Sample);
+    fn get_mod_matrix(&self) -> &ModMatrix<Self::Param>;
+    fn get_mod_matrix_mut(&mut self) -> &mut ModMatrix<Self::Param>;
+    // The lfos behind ModSource::Lfo1 and Lfo2, shared by every voice
+    fn get_mod_lfos_mut(&mut self) -> &mut [Lfo; 2];
+    // Reads a parameter with the mod matrix applied for the given source values
+    fn get_modulated_param<T: Parameter>(&self, ty: Self::Param, sources: &ModSources) -> T;
+
     fn clear_events(&mut self);
+    // An EventOverflow error if any note events didn't fit in the event queue since it was
+    // last called. Dropping events doesn't stop run, so check this after it to find out if
+    // any were lost.
+    fn take_dropped_events(&mut self) -> Result<(), DeviceError>;
 }
 
 pub trait Voice {
-    fn run(&self, song_position: usize, buffer: &mut [Sample]) -> Result<usize, DeviceError>;
-    fn note_on(&mut self, note: Note, velocity: u32, detune: Detune, pan: Pan);
-    fn note_off(&mut self);
+    type Param: DeviceParameters;
+    // song_position is the sample buffer starts at, which is where the device has got to
+    // including any event earlier in the same run. Adds the voice into buffer.
+    fn run(
+        &mut self,
+        params: ParamView<Self::Param>,
+        song_position: usize,
+        buffer: &mut [Sample],
+    ) -> Result<usize, DeviceError>;
+    fn note_on(
+        &mut self,
+        params: ParamView<Self::Param>,
+        note: Note,
+        velocity: u32,
+        detune: Detune,
+        pan: Pan,
+    );
+    // Called by note_on once the voice is set up for the note, to start envelopes and so on
+    fn on_note_on(&mut self, _params: ParamView<Self::Param>, _velocity: u32) {}
+    fn note_off(&mut self, params: ParamView<Self::Param>);
     fn note_slide(&mut self, note: Note);
 
     fn is_on(&self) -> bool;
+    // What ModSource::Envelope1 and Envelope2 are for this voice, between 0 and 1
+    fn mod_envelopes(&self) -> [Sample; 2] {
+        [s!(0); 2]
+    }
+    // Whether the voice has stopped sounding, e.g. its release has run out, so the device
+    // can turn it off and give it to the next note
+    fn is_finished(&self) -> bool;
     fn get_note(&mut self) -> Note;
+    // The frequency to play, which is get_note through the voice's tuning
+    fn get_freq(&mut self) -> Freq;
     fn get_detune(&self) -> Detune;
     fn get_pan(&self) -> Pan;
     fn get_vibrato_phase(&self) -> VibratoPhase;
+    fn get_rng(&self) -> Rng;
 
     fn set_detune(&mut self, n: Detune);
     fn set_pan(&mut self, n: Pan);
     fn set_slide(&mut self, n: SlideTime);
+    fn set_tuning(&mut self, n: &'static Tuning);
     fn set_vibrato_phase(&mut self, n: VibratoPhase);
+    fn set_rng(&mut self, n: Rng);
+}
+
+// A synth's voices. Each one starts with its own fork of Rng::default(), the same as
+// seed(1), so unison voices don't all get the same random phase before anything is seeded.
+#[derive(Debug, Clone)]
+pub struct Voices<V, const N: usize>([V; N]);
+
+impl<V: Voice + Default, const N: usize> Default for Voices<V, N> {
+    fn default() -> Self {
+        // The device is being built, so its coefficients are about to depend on the rate
+        lock_sample_rate();
+        let rng = Rng::default();
+        Voices(core::array::from_fn(|i| {
+            let mut voice = V::default();
+            voice.set_rng(rng.fork(i as u32));
+            voice
+        }))
+    }
+}
+
+impl<V, const N: usize> Deref for Voices<V, N> {
+    type Target = [V; N];
+    fn deref(&self) -> &[V; N] {
+        &self.0
+    }
+}
+
+impl<V, const N: usize> DerefMut for Voices<V, N> {
+    fn deref_mut(&mut self) -> &mut [V; N] {
+        &mut self.0
+    }
 }
 
-#[derive(PartialEq, Eq, Debug, Default)]
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy)]
 pub enum EventType {
     #[default]
     None,
@@ -87,10 +307,150 @@ pub enum EventType {
     NoteOff,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy)]
 pub struct Event {
     pub ty: EventType,
     pub delta_samples: usize,
     pub note: Note,
     pub velocity: u32,
 }
+
+// Pending events in the order they happen, with events at the same time kept in the order
+// they were pushed. Events further away than one run just wait for the next one.
+#[derive(Debug, Clone)]
+pub struct EventQueue<const N: usize> {
+    events: [Event; N],
+    len: usize,
+    // Events that didn't fit since the last take_dropped
+    dropped: u32,
+}
+
+impl<const N: usize> Default for EventQueue<N> {
+    fn default() -> Self {
+        EventQueue {
+            events: [Event::default(); N],
+            len: 0,
+            dropped: 0,
+        }
+    }
+}
+
+impl<const N: usize> EventQueue<N> {
+    // Returns false, and counts it as dropped, if the queue is full
+    pub fn push(&mut self, event: Event) -> bool {
+        if self.len == N {
+            self.dropped = self.dropped.saturating_add(1);
+            return false;
+        }
+        // After everything at the same time or earlier, so equal times stay in push order
+        let at = self.events[..self.len]
+            .iter()
+            .position(|e| e.delta_samples > event.delta_samples)
+            .unwrap_or(self.len);
+        self.events.copy_within(at..self.len, at + 1);
+        self.events[at] = event;
+        self.len += 1;
+        true
+    }
+
+    // The first event, if it's due now
+    pub fn pop_due(&mut self) -> Option<Event> {
+        if self.len == 0 || self.events[0].delta_samples > 0 {
+            return None;
+        }
+        let event = self.events[0];
+        self.events.copy_within(1..self.len, 0);
+        self.len -= 1;
+        Some(event)
+    }
+
+    // Samples until the first event, if there is one
+    pub fn next_delta(&self) -> Option<usize> {
+        self.events[..self.len].first().map(|e| e.delta_samples)
+    }
+
+    // Moves every event samples closer
+    pub fn advance(&mut self, samples: usize) {
+        for event in self.events[..self.len].iter_mut() {
+            event.delta_samples = event.delta_samples.saturating_sub(samples);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // How many events were dropped since it was last called
+    pub fn take_dropped(&mut self) -> u32 {
+        core::mem::take(&mut self.dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pico_donk_proc_macro::device;
+
+    device!(
+        #[derive(Copy, Clone, Debug)]
+        pub struct TestParameters {
+            Level: Param,
+            Fine: HalfParam,
+            Root: Note,
+        }
+
+        #[derive(Debug, Default)]
+        pub struct Test {}
+
+        impl Device for Test {
+            fn run(&mut self, song_position: usize, buffer: &mut [Sample]) -> Result<usize, DeviceError> {
+                Ok(song_position + buffer.len())
+            }
+        }
+    );
+
+    #[test]
+    fn half_param_parameters() {
+        let mut device: Test = Default::default();
+        assert_eq!(
+            Test::param_precision(TestParameters::Fine),
+            ParamPrecision::Half
+        );
+
+        device.set_param(TestParameters::Fine, HalfParam::from(hf!(0.25)));
+        assert_eq!(
+            *device.get_param::<HalfParam>(TestParameters::Fine),
+            hf!(0.25)
+        );
+        // Through Param, which is the same 0 to 1
+        assert_eq!(*device.get_param::<Param>(TestParameters::Fine), sf!(0.25));
+        device.set_param(TestParameters::Fine, Param::from(sf!(0.75)));
+        assert_eq!(
+            *device.get_param::<HalfParam>(TestParameters::Fine),
+            hf!(0.75)
+        );
+
+        // A HalfParam can set the other kinds too
+        device.set_param(TestParameters::Level, HalfParam::from(hf!(0.5)));
+        assert_eq!(*device.get_param::<Param>(TestParameters::Level), sf!(0.5));
+        device.set_param(TestParameters::Root, HalfParam::from(hf!(0.5)));
+        assert_eq!(*device.get_param::<Note>(TestParameters::Root), h!(64));
+
+        // And survives the chunk
+        let chunk = device.get_chunk();
+        let mut other: Test = Default::default();
+        other.set_chunk(chunk);
+        assert_eq!(
+            *other.get_param::<HalfParam>(TestParameters::Fine),
+            hf!(0.75)
+        );
+    }
+}
@@ -1,8 +1,8 @@
 use crate::cst::*;
 use crate::helpers::*;
 
-#[derive(Debug, Clone, Copy, Default)]
-enum EnvelopeState {
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnvelopeState {
     Attack,
     Decay,
     Sustain,
@@ -15,10 +15,10 @@ enum EnvelopeState {
 pub struct Envelope {
     state: EnvelopeState,
 
-    attack: Sample,
-    decay: Sample,
+    attack: Stage,
+    decay: Stage,
     sustain: Sample,
-    release: Sample,
+    release: Stage,
 
     pos: Sample,
     release_value: Sample,
@@ -30,74 +30,455 @@ impl Default for Envelope {
     }
 }
 
+// Converts a stage length in milliseconds to the amount pos moves per sample
+fn ms_to_rate(ms: EnvValue) -> Sample {
+    let samples = (ms.to_bits() as i64 * sample_rate() as i64 / (1000 << Half::FRAC_BITS)).max(1);
+    Sample::from_bits((s!(1).to_bits() as i64 / samples).max(1) as i32)
+}
+
+// A stage length in milliseconds as set by the user, and the amount pos moves per sample
+#[derive(Debug, Clone, Copy)]
+struct Stage {
+    ms: EnvValue,
+    rate: Sample,
+}
+
+impl Stage {
+    fn new(ms: EnvValue) -> Stage {
+        Stage {
+            ms,
+            rate: ms_to_rate(ms),
+        }
+    }
+}
+
+// The stage machine Envelope and ExtEnvelope share, pos goes from 0 to 1 through each stage
+trait Stages {
+    fn value(&self) -> Sample;
+    // The per-sample step of the current stage, or None if it doesn't move on by itself
+    fn rate(&self) -> Option<Sample>;
+    fn pos_mut(&mut self) -> &mut Sample;
+    fn next_stage(&mut self);
+}
+
+// Advance by a whole block of samples, moving through as many stages as needed
+fn advance_stages<E: Stages>(envelope: &mut E, mut samples: usize) {
+    while samples > 0 {
+        let rate = match envelope.rate() {
+            Some(rate) => rate,
+            None => return,
+        };
+        let pos = envelope.pos_mut();
+        let remaining = ((s!(1) - *pos).to_bits() + rate.to_bits() - 1) / rate.to_bits();
+        let remaining = remaining.max(1) as usize;
+        if samples < remaining {
+            *pos += Sample::from_bits(rate.to_bits() * samples as i32);
+            return;
+        }
+        samples -= remaining;
+        envelope.next_stage();
+    }
+}
+
+// Write the envelope into buffer, advancing one sample per entry
+fn fill_stages<E: Stages>(envelope: &mut E, buffer: &mut [Sample]) {
+    for s in buffer.iter_mut() {
+        *s = envelope.value();
+        advance_stages(envelope, 1);
+    }
+}
+
 impl Envelope {
-    fn new() -> Envelope {
+    pub fn new() -> Envelope {
         Envelope {
             state: EnvelopeState::Finished,
-            attack: s!(1),
-            decay: s!(5),
+            attack: Stage::new(h!(1).into()),
+            decay: Stage::new(h!(5).into()),
             sustain: sf!(0.5),
-            release: sf!(1.5),
+            release: Stage::new(hf!(1.5).into()),
             pos: s!(0),
-            release_value: sf!(1.5),
+            release_value: s!(0),
         }
     }
-    fn trigger(&mut self) {
+    pub fn trigger(&mut self) {
+        // The attack's value is its pos, so starting it from the current value means
+        // retriggering a sounding note carries on from where it is instead of clicking
+        self.pos = self.get_value().clamp(s!(0), s!(1));
         self.state = EnvelopeState::Attack;
-        self.pos = s!(0);
     }
-    fn off(&mut self) {
-        self.release_value = self.getValue();
+    pub fn off(&mut self) {
+        if self.state == EnvelopeState::Finished {
+            return;
+        }
+        self.release_value = self.get_value();
         self.state = EnvelopeState::Release;
         self.pos = s!(0);
     }
-    fn getValue(&self) -> Sample {
+    pub fn get_value(&self) -> Sample {
         match self.state {
-            EnvelopeState::Attack => self.pos * self.attack,
+            EnvelopeState::Attack => self.pos,
 
             EnvelopeState::Decay => {
-                let f = s!(1) - (self.pos * self.decay);
+                let f = s!(1) - self.pos;
                 let ff = f * f;
-                (ff) + (self.sustain * (s!(1) - ff))
+                ff + (self.sustain * (s!(1) - ff))
             }
 
             EnvelopeState::Sustain => self.sustain,
 
             EnvelopeState::Release => {
-                let f = s!(1) - (self.pos * self.release);
+                let f = s!(1) - self.pos;
                 let ff = f * f;
                 self.release_value * ff
             }
 
-            _ => {
-                s!(0)
+            EnvelopeState::Finished => s!(0),
+        }
+    }
+    pub fn get_state(&self) -> EnvelopeState {
+        self.state
+    }
+    pub fn is_finished(&self) -> bool {
+        self.state == EnvelopeState::Finished
+    }
+
+    // Advance by a single sample
+    #[inline]
+    pub fn next(&mut self) {
+        self.advance(1);
+    }
+
+    // Advance by a whole block of samples, moving through as many stages as needed
+    pub fn advance(&mut self, samples: usize) {
+        advance_stages(self, samples);
+    }
+
+    // Write the envelope into buffer, advancing one sample per entry
+    pub fn fill(&mut self, buffer: &mut [Sample]) {
+        fill_stages(self, buffer);
+    }
+
+    pub fn set_attack(&mut self, ms: EnvValue) {
+        self.attack = Stage::new(ms);
+    }
+    pub fn set_decay(&mut self, ms: EnvValue) {
+        self.decay = Stage::new(ms);
+    }
+    pub fn set_sustain(&mut self, value: Sample) {
+        self.sustain = value;
+    }
+    pub fn set_release(&mut self, ms: EnvValue) {
+        self.release = Stage::new(ms);
+    }
+    pub fn get_attack(&self) -> EnvValue {
+        self.attack.ms
+    }
+    pub fn get_decay(&self) -> EnvValue {
+        self.decay.ms
+    }
+    pub fn get_sustain(&self) -> Sample {
+        self.sustain
+    }
+    pub fn get_release(&self) -> EnvValue {
+        self.release.ms
+    }
+}
+
+impl Stages for Envelope {
+    fn value(&self) -> Sample {
+        self.get_value()
+    }
+    fn rate(&self) -> Option<Sample> {
+        match self.state {
+            EnvelopeState::Attack => Some(self.attack.rate),
+            EnvelopeState::Decay => Some(self.decay.rate),
+            EnvelopeState::Release => Some(self.release.rate),
+            EnvelopeState::Sustain | EnvelopeState::Finished => None,
+        }
+    }
+    fn pos_mut(&mut self) -> &mut Sample {
+        &mut self.pos
+    }
+    fn next_stage(&mut self) {
+        self.pos = s!(0);
+        self.state = match self.state {
+            EnvelopeState::Attack => EnvelopeState::Decay,
+            EnvelopeState::Decay => EnvelopeState::Sustain,
+            EnvelopeState::Sustain => EnvelopeState::Sustain,
+            EnvelopeState::Release | EnvelopeState::Finished => EnvelopeState::Finished,
+        };
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnvelopeCurve {
+    Linear,
+    Exponential,
+    // The shape used by Envelope's decay and release
+    #[default]
+    Squared,
+}
+
+impl EnvelopeCurve {
+    // Maps progress through a stage (0 to 1) onto how far the value has moved (0 to 1)
+    #[inline]
+    pub fn shape(self, pos: Sample) -> Sample {
+        match self {
+            EnvelopeCurve::Linear => pos,
+            EnvelopeCurve::Exponential => {
+                if pos >= s!(1) {
+                    s!(1)
+                } else {
+                    pos.lookup(&FAST_POW_TAB, FAST_POW_TAB_LOG2_SIZE)
+                }
+            }
+            EnvelopeCurve::Squared => {
+                let f = s!(1) - pos;
+                s!(1) - f * f
             }
         }
     }
-    fn next(&mut self) {}
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EnvelopeLoop {
+    #[default]
+    Off,
+    // Go back to attack instead of sustaining, until the note is released
+    AttackDecay,
+    // Same as AttackDecay, but the delay and hold stages are part of the loop
+    DelayAttackHoldDecay,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExtEnvelopeState {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+    #[default]
+    Finished,
+}
 
-    fn set_attack(&mut self, value: Sample) {
-        self.attack = s!(1) / value;
+// A DAHDSR envelope with a curve per stage and optional looping
+#[derive(Debug, Clone, Copy)]
+pub struct ExtEnvelope {
+    state: ExtEnvelopeState,
+
+    delay: Stage,
+    attack: Stage,
+    hold: Stage,
+    decay: Stage,
+    sustain: Sample,
+    release: Stage,
+
+    attack_curve: EnvelopeCurve,
+    decay_curve: EnvelopeCurve,
+    release_curve: EnvelopeCurve,
+    loop_mode: EnvelopeLoop,
+
+    pos: Sample,
+    start_value: Sample,
+    release_value: Sample,
+}
+
+impl Default for ExtEnvelope {
+    fn default() -> Self {
+        ExtEnvelope::new()
+    }
+}
+
+impl ExtEnvelope {
+    pub fn new() -> ExtEnvelope {
+        ExtEnvelope {
+            state: ExtEnvelopeState::Finished,
+            delay: Stage::new(h!(0).into()),
+            attack: Stage::new(h!(1).into()),
+            hold: Stage::new(h!(0).into()),
+            decay: Stage::new(h!(5).into()),
+            sustain: sf!(0.5),
+            release: Stage::new(hf!(1.5).into()),
+            attack_curve: EnvelopeCurve::Linear,
+            decay_curve: EnvelopeCurve::Squared,
+            release_curve: EnvelopeCurve::Squared,
+            loop_mode: EnvelopeLoop::Off,
+            pos: s!(0),
+            start_value: s!(0),
+            release_value: s!(0),
+        }
+    }
+    pub fn trigger(&mut self) {
+        // Start the attack from wherever we are now, so retriggering doesn't click
+        self.start_value = self.get_value();
+        self.enter(ExtEnvelopeState::Delay);
     }
-    fn set_decay(&mut self, value: Sample) {
-        self.decay = s!(1) / value;
+    pub fn off(&mut self) {
+        if self.state == ExtEnvelopeState::Finished {
+            return;
+        }
+        self.release_value = self.get_value();
+        self.enter(ExtEnvelopeState::Release);
+    }
+    pub fn get_value(&self) -> Sample {
+        match self.state {
+            ExtEnvelopeState::Delay => self.start_value,
+            ExtEnvelopeState::Attack => {
+                self.start_value + (s!(1) - self.start_value) * self.attack_curve.shape(self.pos)
+            }
+            ExtEnvelopeState::Hold => s!(1),
+            ExtEnvelopeState::Decay => {
+                s!(1) + (self.sustain - s!(1)) * self.decay_curve.shape(self.pos)
+            }
+            ExtEnvelopeState::Sustain => self.sustain,
+            ExtEnvelopeState::Release => {
+                self.release_value - self.release_value * self.release_curve.shape(self.pos)
+            }
+            ExtEnvelopeState::Finished => s!(0),
+        }
+    }
+    pub fn get_state(&self) -> ExtEnvelopeState {
+        self.state
+    }
+    pub fn is_finished(&self) -> bool {
+        self.state == ExtEnvelopeState::Finished
     }
-    fn set_sustain(&mut self, value: Sample) {
+
+    #[inline]
+    pub fn next(&mut self) {
+        self.advance(1);
+    }
+
+    pub fn advance(&mut self, samples: usize) {
+        advance_stages(self, samples);
+    }
+
+    pub fn fill(&mut self, buffer: &mut [Sample]) {
+        fill_stages(self, buffer);
+    }
+
+    // The stage's length and rate, or None if the stage doesn't move on by itself
+    fn stage(&self, state: ExtEnvelopeState) -> Option<Stage> {
+        match state {
+            ExtEnvelopeState::Delay => Some(self.delay),
+            ExtEnvelopeState::Attack => Some(self.attack),
+            ExtEnvelopeState::Hold => Some(self.hold),
+            ExtEnvelopeState::Decay => Some(self.decay),
+            ExtEnvelopeState::Release => Some(self.release),
+            ExtEnvelopeState::Sustain | ExtEnvelopeState::Finished => None,
+        }
+    }
+
+    // Move into a stage, skipping over any stages that have been set to zero length
+    fn enter(&mut self, mut state: ExtEnvelopeState) {
+        self.pos = s!(0);
+        // A loop made entirely of empty stages would never settle, so give up and sustain
+        for _ in 0..4 {
+            if self.stage(state).map_or(true, |stage| *stage.ms > 0) {
+                self.state = state;
+                return;
+            }
+            state = self.after(state);
+        }
+        self.state = ExtEnvelopeState::Sustain;
+    }
+
+    fn after(&self, state: ExtEnvelopeState) -> ExtEnvelopeState {
+        match (state, self.loop_mode) {
+            (ExtEnvelopeState::Delay, _) => ExtEnvelopeState::Attack,
+            (ExtEnvelopeState::Attack, _) => ExtEnvelopeState::Hold,
+            (ExtEnvelopeState::Hold, _) => ExtEnvelopeState::Decay,
+            (ExtEnvelopeState::Decay, EnvelopeLoop::Off) => ExtEnvelopeState::Sustain,
+            (ExtEnvelopeState::Decay, EnvelopeLoop::AttackDecay) => ExtEnvelopeState::Attack,
+            (ExtEnvelopeState::Decay, EnvelopeLoop::DelayAttackHoldDecay) => {
+                ExtEnvelopeState::Delay
+            }
+            (ExtEnvelopeState::Sustain, _) => ExtEnvelopeState::Sustain,
+            (ExtEnvelopeState::Release, _) | (ExtEnvelopeState::Finished, _) => {
+                ExtEnvelopeState::Finished
+            }
+        }
+    }
+
+    pub fn set_delay(&mut self, ms: EnvValue) {
+        self.delay = Stage::new(ms);
+    }
+    pub fn set_attack(&mut self, ms: EnvValue) {
+        self.attack = Stage::new(ms);
+    }
+    pub fn set_hold(&mut self, ms: EnvValue) {
+        self.hold = Stage::new(ms);
+    }
+    pub fn set_decay(&mut self, ms: EnvValue) {
+        self.decay = Stage::new(ms);
+    }
+    pub fn set_sustain(&mut self, value: Sample) {
         self.sustain = value;
     }
-    fn set_release(&mut self, value: Sample) {
-        self.release = s!(1) / value;
+    pub fn set_release(&mut self, ms: EnvValue) {
+        self.release = Stage::new(ms);
+    }
+    pub fn set_attack_curve(&mut self, curve: EnvelopeCurve) {
+        self.attack_curve = curve;
     }
-    fn get_attack(&mut self) -> Sample {
-        s!(1) / self.attack
+    pub fn set_decay_curve(&mut self, curve: EnvelopeCurve) {
+        self.decay_curve = curve;
     }
-    fn get_decay(&mut self) -> Sample {
-        s!(1) / self.decay
+    pub fn set_release_curve(&mut self, curve: EnvelopeCurve) {
+        self.release_curve = curve;
     }
-    fn get_sustain(&mut self) -> Sample {
+    pub fn set_loop_mode(&mut self, mode: EnvelopeLoop) {
+        self.loop_mode = mode;
+    }
+    pub fn get_delay(&self) -> EnvValue {
+        self.delay.ms
+    }
+    pub fn get_attack(&self) -> EnvValue {
+        self.attack.ms
+    }
+    pub fn get_hold(&self) -> EnvValue {
+        self.hold.ms
+    }
+    pub fn get_decay(&self) -> EnvValue {
+        self.decay.ms
+    }
+    pub fn get_sustain(&self) -> Sample {
         self.sustain
     }
-    fn get_release(&mut self) -> Sample {
-        s!(1) / self.release
+    pub fn get_release(&self) -> EnvValue {
+        self.release.ms
+    }
+    pub fn get_attack_curve(&self) -> EnvelopeCurve {
+        self.attack_curve
+    }
+    pub fn get_decay_curve(&self) -> EnvelopeCurve {
+        self.decay_curve
+    }
+    pub fn get_release_curve(&self) -> EnvelopeCurve {
+        self.release_curve
+    }
+    pub fn get_loop_mode(&self) -> EnvelopeLoop {
+        self.loop_mode
+    }
+}
+
+impl Stages for ExtEnvelope {
+    fn value(&self) -> Sample {
+        self.get_value()
+    }
+    fn rate(&self) -> Option<Sample> {
+        self.stage(self.state).map(|stage| stage.rate)
+    }
+    fn pos_mut(&mut self) -> &mut Sample {
+        &mut self.pos
+    }
+    fn next_stage(&mut self) {
+        let next = self.after(self.state);
+        if next == ExtEnvelopeState::Delay || next == ExtEnvelopeState::Attack {
+            // Looping round, so attack from the sustain level the decay ended on
+            self.start_value = self.get_value();
+        }
+        self.enter(next);
     }
 }
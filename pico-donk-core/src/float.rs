@@ -0,0 +1,487 @@
+// A floating point stand in for FixedI32, used as a reference to measure the fixed point
+// maths against. Turned on with the float feature, which swaps Sample, Half and Quarter
+// over to Float, so everything else builds unchanged.
+// Bits are still value * 2^FRAC, so from_bits/to_bits, s!() and the chunk code all work,
+// and the range is the same as the fixed type it replaces. Only the precision changes.
+
+use core::cmp::Ordering;
+use core::fmt;
+use core::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+use core::str::FromStr;
+
+use crate::helpers::*;
+
+#[derive(Clone, Copy, Default)]
+pub struct Float<const FRAC: i32>(f64);
+
+// Compared with total_cmp so Eq and Ord hold like they do for FixedI32. Adding 0 turns
+// -0 into 0 first, so the two still compare equal like the fixed zero they stand in for.
+impl<const FRAC: i32> Ord for Float<FRAC> {
+    #[inline]
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.0 + 0.0).total_cmp(&(other.0 + 0.0))
+    }
+}
+
+impl<const FRAC: i32> PartialOrd for Float<FRAC> {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<const FRAC: i32> PartialEq for Float<FRAC> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<const FRAC: i32> Eq for Float<FRAC> {}
+
+impl<const FRAC: i32> fmt::Debug for Float<FRAC> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl<const FRAC: i32> fmt::Display for Float<FRAC> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl<const FRAC: i32> FromStr for Float<FRAC> {
+    type Err = core::num::ParseFloatError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::saturating_from_num(s.parse::<f64>()?))
+    }
+}
+
+// Anything from_num can take
+pub trait ToF64 {
+    fn to_f64(self) -> f64;
+}
+
+// Anything to_num can give
+pub trait FromF64 {
+    fn from_f64(x: f64) -> Self;
+}
+
+macro_rules! float_casts {
+    ($($type: ty),*) => {
+        $(
+            impl ToF64 for $type {
+                #[inline]
+                fn to_f64(self) -> f64 {
+                    self as f64
+                }
+            }
+            impl FromF64 for $type {
+                // Rounds towards negative infinity, like FixedI32::to_num
+                #[inline]
+                fn from_f64(x: f64) -> Self {
+                    libm::floor(x) as $type
+                }
+            }
+        )*
+    };
+}
+float_casts!(i8, i16, i32, i64, u8, u16, u32, u64, usize);
+
+impl ToF64 for f32 {
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+impl FromF64 for f32 {
+    #[inline]
+    fn from_f64(x: f64) -> Self {
+        x as f32
+    }
+}
+impl ToF64 for f64 {
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
+impl FromF64 for f64 {
+    #[inline]
+    fn from_f64(x: f64) -> Self {
+        x
+    }
+}
+impl<const FRAC: i32> ToF64 for Float<FRAC> {
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self.0
+    }
+}
+impl<const FRAC: i32> FromF64 for Float<FRAC> {
+    #[inline]
+    fn from_f64(x: f64) -> Self {
+        Float(x)
+    }
+}
+
+// libm::floor isn't const
+const fn const_floor(x: f64) -> i64 {
+    let t = x as i64;
+    if (t as f64) > x {
+        t - 1
+    } else {
+        t
+    }
+}
+
+impl<const FRAC: i32> Float<FRAC> {
+    pub const FRAC_BITS: u32 = FRAC as u32;
+    const SCALE: f64 = (1_i64 << FRAC) as f64;
+    pub const ZERO: Self = Float(0.0);
+    pub const MIN: Self = Float(i32::MIN as f64 / Self::SCALE);
+    pub const MAX: Self = Float(i32::MAX as f64 / Self::SCALE);
+
+    #[inline]
+    pub const fn from_bits(bits: i32) -> Self {
+        Float(bits as f64 / Self::SCALE)
+    }
+    #[inline]
+    pub const fn to_bits(self) -> i32 {
+        let bits = const_floor(self.0 * Self::SCALE);
+        if bits > i32::MAX as i64 {
+            i32::MAX
+        } else if bits < i32::MIN as i64 {
+            i32::MIN
+        } else {
+            bits as i32
+        }
+    }
+
+    #[inline]
+    pub fn from_num<T: ToF64>(x: T) -> Self {
+        Float(x.to_f64())
+    }
+    #[inline]
+    pub fn saturating_from_num<T: ToF64>(x: T) -> Self {
+        Float(x.to_f64()).clamp(Self::MIN, Self::MAX)
+    }
+    #[inline]
+    pub fn wrapping_from_num<T: ToF64>(x: T) -> Self {
+        Float(x.to_f64()).wrap()
+    }
+    #[inline]
+    pub fn to_num<T: FromF64>(self) -> T {
+        T::from_f64(self.0)
+    }
+
+    // Folds back into the range of the fixed type, the way two's complement overflow would
+    #[inline]
+    fn wrap(self) -> Self {
+        let range = Self::MAX.0 - Self::MIN.0;
+        Float(self.0 - libm::floor((self.0 - Self::MIN.0) / range) * range)
+    }
+
+    #[inline]
+    pub fn floor(self) -> Self {
+        Float(libm::floor(self.0))
+    }
+    #[inline]
+    pub fn frac(self) -> Self {
+        Float(self.0 - libm::floor(self.0))
+    }
+    #[inline]
+    pub fn abs(self) -> Self {
+        Float(libm::fabs(self.0))
+    }
+    #[inline]
+    pub fn is_negative(self) -> bool {
+        self.0 < 0.0
+    }
+    #[inline]
+    pub fn is_positive(self) -> bool {
+        self.0 > 0.0
+    }
+    #[inline]
+    pub fn int_log2(self) -> i32 {
+        libm::floor(libm::log2(self.0)) as i32
+    }
+    #[inline]
+    pub fn lerp<const RANGE_FRAC: i32>(
+        self,
+        start: Float<RANGE_FRAC>,
+        end: Float<RANGE_FRAC>,
+    ) -> Float<RANGE_FRAC> {
+        Float(start.0 + self.0 * (end.0 - start.0))
+    }
+    #[inline]
+    pub fn clamp(self, min: Self, max: Self) -> Self {
+        self.max(min).min(max)
+    }
+    #[inline]
+    pub fn min(self, other: Self) -> Self {
+        if other < self {
+            other
+        } else {
+            self
+        }
+    }
+    #[inline]
+    pub fn max(self, other: Self) -> Self {
+        if other > self {
+            other
+        } else {
+            self
+        }
+    }
+    #[inline]
+    pub fn wrapping_add(self, other: Self) -> Self {
+        Float(self.0 + other.0).wrap()
+    }
+    #[inline]
+    pub fn wrapping_sub(self, other: Self) -> Self {
+        Float(self.0 - other.0).wrap()
+    }
+    #[inline]
+    pub fn wrapping_mul_int(self, other: i32) -> Self {
+        Float(self.0 * other as f64).wrap()
+    }
+    #[inline]
+    pub fn saturating_add(self, other: Self) -> Self {
+        Float(self.0 + other.0).clamp(Self::MIN, Self::MAX)
+    }
+    #[inline]
+    pub fn saturating_mul(self, other: Self) -> Self {
+        Float(self.0 * other.0).clamp(Self::MIN, Self::MAX)
+    }
+}
+
+macro_rules! float_ops {
+    ($($op: ident, $fn: ident, $op_assign: ident, $fn_assign: ident, $sym: tt);*) => {
+        $(
+            impl<const FRAC: i32> $op for Float<FRAC> {
+                type Output = Self;
+                #[inline]
+                fn $fn(self, other: Self) -> Self {
+                    Float(self.0 $sym other.0)
+                }
+            }
+            impl<const FRAC: i32> $op<i32> for Float<FRAC> {
+                type Output = Self;
+                #[inline]
+                fn $fn(self, other: i32) -> Self {
+                    Float(self.0 $sym other as f64)
+                }
+            }
+            impl<const FRAC: i32> $op_assign for Float<FRAC> {
+                #[inline]
+                fn $fn_assign(&mut self, other: Self) {
+                    self.0 = self.0 $sym other.0;
+                }
+            }
+            impl<const FRAC: i32> $op_assign<i32> for Float<FRAC> {
+                #[inline]
+                fn $fn_assign(&mut self, other: i32) {
+                    self.0 = self.0 $sym other as f64;
+                }
+            }
+        )*
+    };
+}
+float_ops!(
+    Add, add, AddAssign, add_assign, +;
+    Sub, sub, SubAssign, sub_assign, -;
+    Mul, mul, MulAssign, mul_assign, *;
+    Div, div, DivAssign, div_assign, /
+);
+
+impl<const FRAC: i32> Neg for Float<FRAC> {
+    type Output = Self;
+    #[inline]
+    fn neg(self) -> Self {
+        Float(-self.0)
+    }
+}
+
+impl<const FRAC: i32> PartialEq<i32> for Float<FRAC> {
+    #[inline]
+    fn eq(&self, other: &i32) -> bool {
+        self.0 == *other as f64
+    }
+}
+
+impl<const FRAC: i32> PartialOrd<i32> for Float<FRAC> {
+    #[inline]
+    fn partial_cmp(&self, other: &i32) -> Option<Ordering> {
+        self.0.partial_cmp(&(*other as f64))
+    }
+}
+
+impl<const FRAC: i32> const ConstFromNum for Float<FRAC> {
+    #[inline]
+    fn const_from_num(x: f64) -> Self {
+        Float(x)
+    }
+}
+
+impl<const FRAC: i32> Rand for Float<FRAC> {
+    #[inline]
+    fn rand(rng: &mut Rng) -> Self {
+        Self::from_bits((rng.next_u32() >> (32 - FRAC)) as i32)
+    }
+}
+
+impl<const FRAC: i32> FixedSqrt for Float<FRAC> {
+    fn sqrt(&self) -> Self {
+        if self.is_negative() {
+            panic!("square root of a negative number");
+        }
+        Float(libm::sqrt(self.0))
+    }
+    #[inline]
+    fn checked_sqrt(&self) -> Result<Self, MathError> {
+        if self.is_negative() {
+            Err(MathError::NegativeSqrt)
+        } else {
+            Ok(self.sqrt())
+        }
+    }
+    #[inline]
+    fn saturating_sqrt(&self) -> Self {
+        if self.is_negative() {
+            Self::ZERO
+        } else {
+            self.sqrt()
+        }
+    }
+}
+
+impl<const FRAC1: i32, const FRAC2: i32> MoliveDiv<Float<FRAC2>> for Float<FRAC1> {
+    #[inline]
+    fn molive_div(&self, x: Float<FRAC2>) -> Self {
+        if x.0 == 0.0 {
+            panic!("divide by zero");
+        }
+        Float(self.0 / x.0)
+    }
+    #[inline]
+    fn checked_molive_div(&self, x: Float<FRAC2>) -> Result<Self, MathError> {
+        if x.0 == 0.0 {
+            return Err(MathError::DivideByZero);
+        }
+        let n = Float(self.0 / x.0);
+        if n < Self::MIN || n > Self::MAX {
+            Err(MathError::Overflow)
+        } else {
+            Ok(n)
+        }
+    }
+    #[inline]
+    fn saturating_molive_div(&self, x: Float<FRAC2>) -> Self {
+        match self.checked_molive_div(x) {
+            Ok(n) => n,
+            Err(_) => {
+                if self.is_negative() != x.is_negative() {
+                    Self::MIN
+                } else {
+                    Self::MAX
+                }
+            }
+        }
+    }
+}
+
+// Still goes through the tables, so wavetables and envelope curves behave the same,
+// but interpolates without losing any bits
+impl<const FRAC: i32> TableLookup for Float<FRAC> {
+    #[inline]
+    fn checked_lookup(self, table: &[Self], table_log_size: usize) -> Result<Self, MathError> {
+        if table.len() <= (1 << table_log_size) {
+            Err(MathError::OutsideTable)
+        } else {
+            Ok(self.lookup(table, table_log_size))
+        }
+    }
+    fn lookup(self, table: &[Self], table_log_size: usize) -> Self {
+        let position = self.frac().0 * (1 << table_log_size) as f64;
+        let index = position as usize;
+        let mix = position - index as f64;
+        let left = table[index];
+        let right = table[index + 1];
+        Float(left.0 + (right.0 - left.0) * mix)
+    }
+}
+
+// The rest are worked out exactly rather than from the tables, that's the point of them
+
+impl<const FRAC: i32> Exp for Float<FRAC> {
+    #[inline]
+    fn exp2(self) -> Self {
+        Float(libm::exp2(self.0))
+    }
+    #[inline]
+    fn exp10(self) -> Self {
+        Float(libm::exp10(self.0))
+    }
+    #[inline]
+    fn log2(self) -> Self {
+        if self <= 0 {
+            return Self::MIN;
+        }
+        Float(libm::log2(self.0))
+    }
+    #[inline]
+    fn ln(self) -> Self {
+        if self <= 0 {
+            return Self::MIN;
+        }
+        Float(libm::log(self.0))
+    }
+    #[inline]
+    fn log10(self) -> Self {
+        if self <= 0 {
+            return Self::MIN;
+        }
+        Float(libm::log10(self.0))
+    }
+}
+
+impl<const FRAC: i32> SinCos for Float<FRAC> {
+    // Same shape as FAST_SIN_TAB, one cycle per unit, between 1 and 2
+    #[inline]
+    fn cos(self) -> Self {
+        self.wrapping_add(Float(0.25)).sin()
+    }
+    #[inline]
+    fn sin(self) -> Self {
+        Float(libm::sin(self.frac().0 * 2.0 * core::f64::consts::PI) * 0.5 + 1.5)
+    }
+}
+
+impl<const FRAC: i32> Squares for Float<FRAC> {
+    #[inline]
+    fn square_135(self) -> Self {
+        self.sin() + (self * 3).sin() / 3 + (self * 5).sin() / 5
+    }
+    #[inline]
+    fn square_35(self) -> Self {
+        (self * 3).sin() / 3 + (self * 5).sin() / 5
+    }
+}
+
+impl<const FRAC: i32> Saturate for Float<FRAC> {
+    #[inline]
+    fn tanh(self) -> Self {
+        Float(libm::tanh(self.0))
+    }
+    #[inline]
+    fn soft_clip(self) -> Self {
+        let x = self.0.clamp(-1.0, 1.0);
+        Float(x * 1.5 - x * x * x * 0.5)
+    }
+    #[inline]
+    fn asymmetric(self, bias: Self) -> Self {
+        (self + bias).tanh() - bias.tanh()
+    }
+}
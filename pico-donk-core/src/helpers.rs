@@ -28,24 +28,166 @@ impl<const FRAC: i32> const ConstFromNum for FixedI32<FRAC> {
     }
 }
 
-static mut RANDOM_SEED: i32 = 1;
+// A small xorshift generator. Every device and voice owns its own, so renders don't
+// depend on what else is running and nothing is shared between cores.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Rng {
+    state: u32,
+}
+
+impl const Default for Rng {
+    fn default() -> Self {
+        Rng::new(1)
+    }
+}
+
+impl Rng {
+    pub const fn new(seed: u32) -> Rng {
+        // xorshift gets stuck on zero
+        Rng {
+            state: if seed == 0 { 0x9E3779B9 } else { seed },
+        }
+    }
+    // Seed from a position in the song, so rendering from the same point sounds the same
+    pub const fn from_song_position(song_position: usize, salt: u32) -> Rng {
+        Rng::new((song_position as u32).wrapping_mul(0x9E3779B9) ^ salt)
+    }
+    // A new generator for something owned by this one, e.g. voice number n
+    pub fn fork(&self, n: u32) -> Rng {
+        let mut rng = Rng::new(self.state ^ n.wrapping_add(1).wrapping_mul(0x85EBCA6B));
+        rng.next_u32();
+        rng
+    }
+    #[inline]
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+    // White noise between -1 and 1
+    #[inline]
+    pub fn white(&mut self) -> Sample {
+        Sample::from_bits((self.next_u32() as i32) >> (32 - 1 - Sample::FRAC_BITS))
+    }
+}
+
+// Song positions, as passed to and returned from Device::run, count samples from the start
+// of the song at the current sample rate. These turn them into the units people think in.
+
+// Saturates at about 9 hours
+pub fn position_to_seconds(song_position: usize) -> Half {
+    let bits = ((song_position as i64) << Half::FRAC_BITS) / sample_rate() as i64;
+    Half::from_bits(bits.min(i32::MAX as i64) as i32)
+}
+
+// How many beats into the song at tempo bpm
+pub fn position_to_beats(song_position: usize, tempo: u32) -> Half {
+    let bits =
+        ((song_position as i64 * tempo as i64) << Half::FRAC_BITS) / (60 * sample_rate() as i64);
+    Half::from_bits(bits.min(i32::MAX as i64) as i32)
+}
+
+// The sample a beat starts on at tempo bpm, e.g. to schedule a note_on's delta_samples
+pub fn beats_to_position(beats: Half, tempo: u32) -> usize {
+    let samples = (beats.to_bits().max(0) as i64 * 60 * sample_rate() as i64)
+        / ((tempo.max(1) as i64) << Half::FRAC_BITS);
+    samples as usize
+}
 
 pub trait Rand {
-    fn rand() -> Self;
+    // A number between 0 and 1
+    fn rand(rng: &mut Rng) -> Self;
 }
 
 impl<const FRAC: i32> Rand for FixedI32<FRAC> {
     #[inline]
-    fn rand() -> Self {
-        unsafe {
-            RANDOM_SEED = RANDOM_SEED.wrapping_mul(0x15a4e35);
-            Self::from_bits(RANDOM_SEED & 0x3FFF << 16 | RANDOM_SEED & -65536 >> 16)
+    fn rand(rng: &mut Rng) -> Self {
+        Self::from_bits((rng.next_u32() >> (32 - FRAC)) as i32)
+    }
+}
+
+// Paul Kellet's economy pink noise filter
+#[derive(Clone, Copy, Eq, PartialEq, Default, Debug)]
+pub struct PinkNoise {
+    b0: Sample,
+    b1: Sample,
+    b2: Sample,
+}
+
+impl PinkNoise {
+    #[inline]
+    pub fn next(&mut self, rng: &mut Rng) -> Sample {
+        let white = rng.white();
+        self.b0 = self.b0 * sf!(0.99765) + white * sf!(0.0990460);
+        self.b1 = self.b1 * sf!(0.96300) + white * sf!(0.2965164);
+        self.b2 = self.b2 * sf!(0.57000) + white * sf!(1.0526913);
+        (self.b0 + self.b1 + self.b2 + white * sf!(0.1848)) * sf!(0.2)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathError {
+    NegativeSqrt,
+    DivideByZero,
+    Overflow,
+    OutsideTable,
+}
+
+// Keeps track of the range a value has covered, and how often it went outside the range
+// it should be in. Only does anything with debug assertions on, so it's free on the pico.
+#[derive(Debug, Clone, Copy)]
+pub struct RangeTracker<T: Copy + PartialOrd> {
+    pub lo: T,
+    pub hi: T,
+    pub min: Option<T>,
+    pub max: Option<T>,
+    pub violations: u32,
+}
+
+impl<T: Copy + PartialOrd> RangeTracker<T> {
+    pub const fn new(lo: T, hi: T) -> Self {
+        RangeTracker {
+            lo,
+            hi,
+            min: None,
+            max: None,
+            violations: 0,
         }
     }
+    // Passes value straight through, noting it down on the way
+    #[inline]
+    pub fn track(&mut self, value: T) -> T {
+        #[cfg(debug_assertions)]
+        {
+            if self.min.map_or(true, |m| value < m) {
+                self.min = Some(value);
+            }
+            if self.max.map_or(true, |m| value > m) {
+                self.max = Some(value);
+            }
+            if value < self.lo || value > self.hi {
+                self.violations = self.violations.saturating_add(1);
+            }
+        }
+        value
+    }
+    pub fn reset(&mut self) {
+        self.min = None;
+        self.max = None;
+        self.violations = 0;
+    }
 }
 
-pub trait FixedSqrt {
+pub trait FixedSqrt
+where
+    Self: Sized,
+{
+    // Panics on negative numbers
     fn sqrt(&self) -> Self;
+    fn checked_sqrt(&self) -> Result<Self, MathError>;
+    // Negative numbers give zero
+    fn saturating_sqrt(&self) -> Self;
 }
 
 impl<const FRAC: i32> FixedSqrt for FixedI32<FRAC> {
@@ -94,27 +236,89 @@ impl<const FRAC: i32> FixedSqrt for FixedI32<FRAC> {
         debug_assert!(n.count_ones() == 0 || n.is_positive());
         n
     }
+    #[inline]
+    fn checked_sqrt(&self) -> Result<Self, MathError> {
+        if self.is_negative() {
+            Err(MathError::NegativeSqrt)
+        } else {
+            Ok(self.sqrt())
+        }
+    }
+    #[inline]
+    fn saturating_sqrt(&self) -> Self {
+        if self.is_negative() {
+            Self::ZERO
+        } else {
+            self.sqrt()
+        }
+    }
 }
 
-pub trait MoliveDiv<const FRAC: i32> {
-    fn molive_div(&self, x: FixedI32<FRAC>) -> Self;
+pub trait MoliveDiv<T>
+where
+    Self: Sized,
+{
+    // Panics when dividing by zero
+    fn molive_div(&self, x: T) -> Self;
+    fn checked_molive_div(&self, x: T) -> Result<Self, MathError>;
+    // Dividing by zero or overflowing gives MIN or MAX, depending on the sign
+    fn saturating_molive_div(&self, x: T) -> Self;
 }
 
-impl<const FRAC1: i32, const FRAC2: i32> const MoliveDiv<FRAC2> for FixedI32<FRAC1> {
+impl<const FRAC1: i32, const FRAC2: i32> const MoliveDiv<FixedI32<FRAC2>> for FixedI32<FRAC1> {
     #[inline]
     fn molive_div(&self, x: FixedI32<FRAC2>) -> Self {
         Self::from_bits((self.to_bits() / x.to_bits()) << (FRAC2))
     }
+    #[inline]
+    fn checked_molive_div(&self, x: FixedI32<FRAC2>) -> Result<Self, MathError> {
+        if x.to_bits() == 0 {
+            return Err(MathError::DivideByZero);
+        }
+        let quotient = match self.to_bits().checked_div(x.to_bits()) {
+            Some(q) => q as i64,
+            None => return Err(MathError::Overflow),
+        };
+        let bits = quotient << FRAC2;
+        if bits > i32::MAX as i64 || bits < i32::MIN as i64 {
+            Err(MathError::Overflow)
+        } else {
+            Ok(Self::from_bits(bits as i32))
+        }
+    }
+    #[inline]
+    fn saturating_molive_div(&self, x: FixedI32<FRAC2>) -> Self {
+        match self.checked_molive_div(x) {
+            Ok(n) => n,
+            Err(_) => {
+                if self.is_negative() != x.is_negative() {
+                    Self::MIN
+                } else {
+                    Self::MAX
+                }
+            }
+        }
+    }
 }
 
 pub trait TableLookup
 where
     Self: Sized,
 {
+    // Panics if the table is shorter than (1 << table_log_size) + 1
     fn lookup(self, table: &[Self], table_log_size: usize) -> Self;
+    fn checked_lookup(self, table: &[Self], table_log_size: usize) -> Result<Self, MathError>;
 }
 
 impl<const FRAC: i32> TableLookup for FixedI32<FRAC> {
+    #[inline]
+    fn checked_lookup(self, table: &[Self], table_log_size: usize) -> Result<Self, MathError> {
+        if table.len() <= (1 << table_log_size) || table_log_size as i32 > (16 - (32 - FRAC) / 2) {
+            Err(MathError::OutsideTable)
+        } else {
+            Ok(self.lookup(table, table_log_size))
+        }
+    }
     fn lookup(self, table: &[Self], table_log_size: usize) -> Self {
         let fract_bits: i32 = FRAC - table_log_size as i32;
         let fract_scale: i32 = 1 << fract_bits;
@@ -138,8 +342,13 @@ impl<const FRAC: i32> TableLookup for FixedI32<FRAC> {
 pub trait Exp {
     fn exp2(self) -> Self;
     fn exp10(self) -> Self;
+    // These return MIN for anything that isn't positive
+    fn log2(self) -> Self;
+    fn ln(self) -> Self;
+    fn log10(self) -> Self;
 }
 
+#[cfg(not(feature = "float"))]
 impl Exp for Sample {
     fn exp2(self) -> Self {
         let scale: Self = if self > 0 {
@@ -154,8 +363,30 @@ impl Exp for Sample {
     fn exp10(self) -> Self {
         (self * sf!(3.32192809489)).exp2()
     }
+    fn log2(self) -> Self {
+        if self <= 0 {
+            return Self::MIN;
+        }
+        // Split into an exponent and a mantissa between 1 and 2
+        let exponent = self.int_log2();
+        let mantissa = if exponent >= 0 {
+            Self::from_bits(self.to_bits() >> exponent)
+        } else {
+            Self::from_bits(self.to_bits() << -exponent)
+        };
+        Self::from_num(exponent) + mantissa.lookup(&FAST_LOG2_TAB, FAST_LOG2_TAB_LOG2_SIZE)
+    }
+    #[inline]
+    fn ln(self) -> Self {
+        self.log2() * sf!(0.69314718056)
+    }
+    #[inline]
+    fn log10(self) -> Self {
+        self.log2() * sf!(0.301029995664)
+    }
 }
 
+#[cfg(not(feature = "float"))]
 impl Exp for Half {
     fn exp2(self) -> Self {
         let shift = self.floor().to_num::<i32>();
@@ -176,6 +407,29 @@ impl Exp for Half {
     fn exp10(self) -> Self {
         (self * hf!(3.32192809489)).exp2()
     }
+    fn log2(self) -> Self {
+        if self <= 0 {
+            return Self::MIN;
+        }
+        // Normalise into Sample so the mantissa keeps all of its fractional bits
+        let exponent = self.int_log2();
+        let shift = exponent + (Sample::FRAC_BITS as i32 - Half::FRAC_BITS as i32);
+        let mantissa = if shift >= 0 {
+            Sample::from_bits(self.to_bits() >> shift)
+        } else {
+            Sample::from_bits(self.to_bits() << -shift)
+        };
+        Self::from_num(exponent)
+            + Self::from_num(mantissa.lookup(&FAST_LOG2_TAB, FAST_LOG2_TAB_LOG2_SIZE))
+    }
+    #[inline]
+    fn ln(self) -> Self {
+        self.log2() * hf!(0.69314718056)
+    }
+    #[inline]
+    fn log10(self) -> Self {
+        self.log2() * hf!(0.301029995664)
+    }
 }
 
 pub trait SinCos {
@@ -184,6 +438,7 @@ pub trait SinCos {
     fn sin(self) -> Self;
 }
 
+#[cfg(not(feature = "float"))]
 impl SinCos for Sample {
     #[inline]
     fn cos(self) -> Self {
@@ -195,6 +450,7 @@ impl SinCos for Sample {
     }
 }
 
+#[cfg(not(feature = "float"))]
 impl SinCos for Half {
     #[inline]
     fn cos(self) -> Self {
@@ -213,6 +469,7 @@ pub trait Squares {
     fn square_35(self) -> Self;
 }
 
+#[cfg(not(feature = "float"))]
 impl Squares for Sample {
     #[inline]
     fn square_135(self) -> Self {
@@ -224,6 +481,7 @@ impl Squares for Sample {
     }
 }
 
+#[cfg(not(feature = "float"))]
 impl Squares for Half {
     #[inline]
     fn square_135(self) -> Self {
@@ -235,6 +493,57 @@ impl Squares for Half {
     }
 }
 
+pub trait Saturate {
+    fn tanh(self) -> Self;
+    // 1.5x - 0.5x^3, hits 1 at x = 1 with a smooth knee and clips past that
+    fn soft_clip(self) -> Self;
+    // tanh with the input shifted by bias, so positive and negative halves distort differently.
+    // The output is shifted back so zero stays at zero.
+    fn asymmetric(self, bias: Self) -> Self;
+}
+
+#[cfg(not(feature = "float"))]
+impl Saturate for Sample {
+    fn tanh(self) -> Self {
+        let x = self.abs();
+        let y = if x >= FAST_TANH_TAB_RANGE {
+            s!(1)
+        } else {
+            (x / FAST_TANH_TAB_RANGE).lookup(&FAST_TANH_TAB, FAST_TANH_TAB_LOG2_SIZE)
+        };
+        if self.is_negative() {
+            -y
+        } else {
+            y
+        }
+    }
+    #[inline]
+    fn soft_clip(self) -> Self {
+        let x = self.clamp(-s!(1), s!(1));
+        x * sf!(1.5) - x * x * x * sf!(0.5)
+    }
+    #[inline]
+    fn asymmetric(self, bias: Self) -> Self {
+        (self + bias).tanh() - bias.tanh()
+    }
+}
+
+#[cfg(not(feature = "float"))]
+impl Saturate for Half {
+    #[inline]
+    fn tanh(self) -> Self {
+        Self::from_num(Sample::saturating_from_num(self).tanh())
+    }
+    #[inline]
+    fn soft_clip(self) -> Self {
+        Self::from_num(Sample::saturating_from_num(self).soft_clip())
+    }
+    #[inline]
+    fn asymmetric(self, bias: Self) -> Self {
+        (self + bias).tanh() - bias.tanh()
+    }
+}
+
 macro_rules! structs {
     ($name: ident, $type: ty) => {
         #[derive(Clone, Copy, Eq, PartialEq, Default, Debug)]
@@ -289,7 +598,7 @@ macro_rules! self_convert {
 structs!(Note, Half);
 structs!(Freq, Half);
 structs!(Db, Half);
-structs!(EnvValue, Sample);
+structs!(EnvValue, Half);
 structs!(Volume, Sample);
 structs!(Param, Sample);
 structs!(HalfParam, Half);
@@ -315,7 +624,7 @@ self_convert!(Pan, Sample);
 self_convert!(Spread, Sample);
 self_convert!(Detune, Sample);
 self_convert!(SlideTime, Quarter);
-self_convert!(EnvValue, Sample);
+self_convert!(EnvValue, Half);
 self_convert!(FalconEnvAmount, Sample);
 
 #[derive(Clone, Copy, Eq, PartialEq, Debug)]
@@ -341,39 +650,42 @@ impl const Default for Unisono {
     }
 }
 
-impl From<Note> for Freq {
-    fn from(note: Note) -> Self {
-        if note.frac() == 0i32 {
-            Half::from_num(NOTE_TAB[note.to_num::<usize>()]).into()
-        } else {
-            Half::from_num(note.frac().lerp(
-                NOTE_TAB[note.to_num::<usize>()],
-                NOTE_TAB[note.to_num::<usize>() + 1],
-            ))
-            .into()
-        }
-    }
-}
-
-// This is only accurate to the nearest octave
+// There's deliberately no From<Note> for Freq, notes become frequencies through a Tuning,
+// see Voice::get_freq. Going back is always the nearest 12-TET note at A440.
 impl From<Freq> for Note {
     #[inline]
     fn from(freq: Freq) -> Self {
-        (((*freq * hf!(0.00227272727273)).int_log2() * 12) + 69).into()
+        // log2 is MIN for 0hz, which unmapped keys in a tuning have, so that's note 0 like
+        // everything else below it
+        if *freq <= h!(0) {
+            return h!(0).into();
+        }
+        // log2(440) = 8.78135971352
+        ((freq.log2() - hf!(8.78135971352)) * h!(12) + h!(69))
+            .max(h!(0))
+            .into()
     }
 }
 
+// 20 * log10(2) = 6.02059991328 db per doubling
+// Silence, and anything quieter than this, comes out as this many db
+pub const DB_FLOOR: Half = hf!(-96.0);
+
 impl From<Db> for Half {
     #[inline]
     fn from(db: Db) -> Self {
-        (*db * hf!(0.166666666667)).exp2()
+        (*db * hf!(0.166096404744)).exp2()
     }
 }
 
 impl From<Half> for Db {
     #[inline]
     fn from(half: Half) -> Self {
-        (half.int_log2() * h!(6)).into()
+        // log2 is MIN for a gain of 0, which would overflow the multiply
+        if half <= h!(0) {
+            return DB_FLOOR.into();
+        }
+        (half.log2() * hf!(6.02059991328)).max(DB_FLOOR).into()
     }
 }
 
@@ -388,7 +700,7 @@ impl From<Volume> for Sample {
 impl From<Sample> for Volume {
     #[inline]
     fn from(sample: Sample) -> Self {
-        (sample.sqrt() * sf!(0.25)).into()
+        (sample.saturating_sqrt() * sf!(0.25)).into()
     }
 }
 
@@ -412,14 +724,127 @@ where
         + From<FalconEnvAmount>
         + Into<Param>
         + From<Param>
+        + Into<HalfParam>
+        + From<HalfParam>
         + Into<VibratoFreq>
         + From<VibratoFreq>,
 {
 }
 
 impl Parameter for Param {}
-//TODO
-//impl Parameter for HalfParam {}
+impl Parameter for HalfParam {}
+
+// How a parameter is kept in a device's chunk. Sample ones hold a Param (0 to 1, with 24 bits
+// after the point). Half ones hold the value itself with 16 bits after the point, for wide
+// ranged things like notes and frequencies where going through a Param would cost precision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamPrecision {
+    Sample,
+    Half,
+}
+
+// Implemented by everything that can be a field of a device's Parameters struct
+pub trait ParamStorage
+where
+    Self: Sized,
+{
+    const PRECISION: ParamPrecision;
+    fn to_chunk(self) -> i32;
+    fn from_chunk(bits: i32) -> Self;
+}
+
+macro_rules! sample_storage {
+    ($($type: ty),*) => {
+        $(
+            impl ParamStorage for $type {
+                const PRECISION: ParamPrecision = ParamPrecision::Sample;
+                #[inline]
+                fn to_chunk(self) -> i32 {
+                    Param::from(self).to_bits()
+                }
+                #[inline]
+                fn from_chunk(bits: i32) -> Self {
+                    Param::from(Sample::from_bits(bits)).into()
+                }
+            }
+        )*
+    };
+}
+macro_rules! half_storage {
+    ($($type: ty),*) => {
+        $(
+            impl ParamStorage for $type {
+                const PRECISION: ParamPrecision = ParamPrecision::Half;
+                #[inline]
+                fn to_chunk(self) -> i32 {
+                    self.to_bits()
+                }
+                #[inline]
+                fn from_chunk(bits: i32) -> Self {
+                    Half::from_bits(bits).into()
+                }
+            }
+        )*
+    };
+}
+sample_storage!(Param, bool, Q, SlideTime, FalconEnvAmount, VibratoFreq);
+half_storage!(HalfParam, Note, Freq, EnvValue);
+
+// HalfParam is the same 0 to 1 as Param, so anything it doesn't have its own conversion for
+// goes through Param
+macro_rules! via_param {
+    ($($type: ty),*) => {
+        $(
+            impl From<HalfParam> for $type {
+                #[inline]
+                fn from(p: HalfParam) -> Self {
+                    Param::from(p).into()
+                }
+            }
+            impl From<$type> for HalfParam {
+                #[inline]
+                fn from(t: $type) -> Self {
+                    Param::from(t).into()
+                }
+            }
+        )*
+    };
+}
+via_param!(
+    bool,
+    Q,
+    Freq,
+    SlideTime,
+    EnvValue,
+    FalconEnvAmount,
+    VibratoFreq
+);
+
+// Param runs from 0 to 1 with 24 bits after the point. The mappings below work on its bits
+// with i64s, so nothing gets lost to an intermediate type on the way.
+const PARAM_ONE: i64 = 1 << 24;
+
+#[inline]
+fn param_bits(p: Param) -> i64 {
+    (p.to_bits() as i64).clamp(0, PARAM_ONE)
+}
+
+// The biggest Param that forward maps to at most bits. Every mapping that uses this moves
+// by at least one bit of its own for each bit of Param, so going Param -> T -> Param gets
+// back exactly where it started. It's 25 goes at forward, so keep it out of anything
+// per sample.
+fn invert_param(bits: i64, forward: impl Fn(i64) -> i64) -> Param {
+    let (mut lo, mut hi) = (0, PARAM_ONE);
+    while lo < hi {
+        let mid = (lo + hi + 1) / 2;
+        if forward(mid) <= bits {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    Sample::from_bits(lo as i32).into()
+}
 
 impl From<Param> for bool {
     #[inline]
@@ -439,21 +864,24 @@ impl From<bool> for Param {
     }
 }
 
-//TODO: This needs optimising for data retention
+// 20Hz to 20kHz, mostly squared but with 1/64 of a straight line mixed in,
+// so every step of Param moves the frequency
+#[inline]
+fn freq_bits(p: i64) -> i64 {
+    (20 << 16) + (((p * p * 63 + p * PARAM_ONE) / 64 * 19980) >> 32)
+}
+
 impl From<Param> for Freq {
     #[inline]
     fn from(p: Param) -> Self {
-        (Half::from_num(*p * *p) * h!(19980) + h!(20)).into()
+        Half::from_bits(freq_bits(param_bits(p)) as i32).into()
     }
 }
 
-//TODO: This needs optimising for data retention
 impl From<Freq> for Param {
     #[inline]
     fn from(f: Freq) -> Self {
-        Sample::from_num((*f - h!(20)) * hf!(0.0000500500500501))
-            .sqrt()
-            .into()
+        invert_param(f.to_bits() as i64, freq_bits)
     }
 }
 
@@ -480,33 +908,40 @@ impl From<Q> for Param {
     }
 }
 
+// 0 to 480000 samples, p^4 plus a straight line of 65536 samples, which is the least
+// that gives a step of SlideTime for every step of Param
+#[inline]
+fn slide_time_bits(p: i64) -> i64 {
+    let p2 = (p * p) >> 24;
+    p + ((((p2 * p2) >> 24) * (480000 - 65536)) >> 16)
+}
+
 impl From<Param> for SlideTime {
     #[inline]
     fn from(p: Param) -> Self {
-        let m: Half = Half::from_num(*p);
-        (Quarter::from_num(m * m * m * m) * q!(480000)).into()
+        Quarter::from_bits(slide_time_bits(param_bits(p)) as i32).into()
     }
 }
 
 impl From<SlideTime> for Param {
     #[inline]
     fn from(q: SlideTime) -> Self {
-        let m: Half = Half::from_num(*q / q!(480000));
-        Sample::from_num(m.sqrt().sqrt()).into()
+        invert_param(q.to_bits() as i64, slide_time_bits)
     }
 }
 
+// Param covers every midi note, 0 to 128
 impl From<HalfParam> for Note {
     #[inline]
     fn from(p: HalfParam) -> Self {
-        (*p).into()
+        (*p * 128).into()
     }
 }
 
 impl From<Note> for HalfParam {
     #[inline]
     fn from(q: Note) -> Self {
-        (*q).into()
+        (*q / 128).into()
     }
 }
 
@@ -524,36 +959,39 @@ impl From<Param> for HalfParam {
     }
 }
 
-// WARNING: Imprecise
+// Note has one bit for every two of Param, so this comes back to within one bit
 impl From<Param> for Note {
     #[inline]
     fn from(p: Param) -> Self {
-        let t: HalfParam = p.into();
-        t.into()
+        Half::from_bits((param_bits(p) >> 1) as i32).into()
     }
 }
 
-// WARNING: Imprecise
 impl From<Note> for Param {
     #[inline]
     fn from(q: Note) -> Self {
-        let t: HalfParam = q.into();
-        t.into()
+        Sample::from_bits(((q.to_bits() as i64) << 1).clamp(0, PARAM_ONE) as i32).into()
     }
 }
 
+// EnvValue is a time in milliseconds, between 1 and 5001. Mostly squared, with 1/16 of a
+// straight line so every step of Param moves it.
+#[inline]
+fn env_value_bits(p: i64) -> i64 {
+    (1 << 16) + (((p * p * 15 + p * PARAM_ONE) / 16 * 5000) >> 32)
+}
+
 impl From<EnvValue> for Param {
     #[inline]
     fn from(ev: EnvValue) -> Self {
-        ((*ev - s!(1)) * sf!(0.0002)).sqrt().into()
+        invert_param(ev.to_bits() as i64, env_value_bits)
     }
 }
 
 impl From<Param> for EnvValue {
     #[inline]
-    fn from(sample: Param) -> Self {
-        let half = Half::from_num(*sample);
-        Sample::from_num(half * half * h!(5000) + h!(1)).into()
+    fn from(p: Param) -> Self {
+        Half::from_bits(env_value_bits(param_bits(p)) as i32).into()
     }
 }
 
@@ -571,18 +1009,23 @@ impl From<Param> for FalconEnvAmount {
     }
 }
 
+// 7Hz to 77Hz, mostly squared with 1/32 of a straight line so every step of Param moves it
+#[inline]
+fn vibrato_freq_bits(p: i64) -> i64 {
+    (7 << 24) + (((p * p * 31 + p * PARAM_ONE) / 32 * 70) >> 24)
+}
+
 impl From<VibratoFreq> for Param {
     #[inline]
     fn from(vf: VibratoFreq) -> Self {
-        let d = ((*vf * sf!(1.0 / (70.0))) - sf!(0.1));
-        (if d >= s!(0) { d.sqrt() } else { s!(0) }).into()
+        invert_param(vf.to_bits() as i64, vibrato_freq_bits)
     }
 }
 
 impl From<Param> for VibratoFreq {
     #[inline]
-    fn from(fr: Param) -> Self {
-        (((*fr * *fr) + sf!(0.1)) * s!(70)).into()
+    fn from(p: Param) -> Self {
+        Sample::from_bits(vibrato_freq_bits(param_bits(p)) as i32).into()
     }
 }
 
@@ -619,3 +1062,70 @@ macro_rules! defaults {
         $self.vibrato_amount = 0.into();
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every 61st Param plus both ends, which hits each of the low bits of Param
+    fn params() -> impl Iterator<Item = Param> {
+        (0..=PARAM_ONE)
+            .step_by(61)
+            .chain([1, 2, PARAM_ONE - 1, PARAM_ONE])
+            .map(|b| Sample::from_bits(b as i32).into())
+    }
+
+    fn check_round_trip<T: From<Param> + Into<Param>>(name: &str) {
+        for p in params() {
+            let back: Param = T::from(p).into();
+            let error = (back.to_bits() - p.to_bits()).abs();
+            assert!(error <= 1, "{}: {:?} came back as {:?}", name, *p, *back);
+        }
+    }
+
+    #[test]
+    fn freq_round_trip() {
+        check_round_trip::<Freq>("Freq");
+    }
+
+    #[test]
+    fn slide_time_round_trip() {
+        check_round_trip::<SlideTime>("SlideTime");
+    }
+
+    #[test]
+    fn env_value_round_trip() {
+        check_round_trip::<EnvValue>("EnvValue");
+    }
+
+    #[test]
+    fn vibrato_freq_round_trip() {
+        check_round_trip::<VibratoFreq>("VibratoFreq");
+    }
+
+    #[test]
+    fn note_round_trip() {
+        check_round_trip::<Note>("Note");
+    }
+
+    #[test]
+    fn silence_is_the_db_floor() {
+        assert_eq!(*Db::from(h!(0)), DB_FLOOR);
+        assert_eq!(*Db::from(-h!(1)), DB_FLOOR);
+        assert_eq!(*Db::from(Half::from_bits(1)), DB_FLOOR);
+        assert!((*Db::from(h!(1))).abs() < hf!(0.1));
+    }
+
+    #[test]
+    fn zero_hz_is_note_0() {
+        assert_eq!(*Note::from(Freq::from(h!(0))), h!(0));
+        assert_eq!(*Note::from(Freq::from(Half::from_bits(1))), h!(0));
+        assert!((*Note::from(Freq::from(h!(440))) - h!(69)).abs() < hf!(0.01));
+    }
+
+    #[test]
+    fn note_covers_midi() {
+        assert_eq!(*Note::from(Param::from(s!(0))), h!(0));
+        assert_eq!(*Note::from(Param::from(s!(1))), h!(128));
+    }
+}
@@ -9,50 +9,96 @@
 pub mod cst;
 #[macro_use]
 pub mod helpers;
+pub mod arpeggiator;
 pub mod device;
 pub mod envelope;
+#[cfg(feature = "float")]
+pub mod float;
+pub mod master;
+pub mod modulation;
+pub mod oscillator;
 pub mod synth;
+pub mod tuning;
+pub mod wavetable;
+
+use cst::*;
+use device::Snapshot;
+use helpers::*;
+use master::*;
 
 #[macro_export]
 macro_rules! note {
     ($x:expr) => {
-        ($x / 48000.0 * 65536.0) as u16
+        Half::const_from_num($x)
     };
 }
 
+#[derive(Clone)]
 pub struct Song {
     current_tempo: u32,
     i: usize,
+    // Where we are in the song at SONG_SAMPLE_RATE, in 16.16 fixed point
+    position: u64,
+    step: u64,
+    increment_scale: u64,
     leads: [u16; 4],
     bass: u16,
     first: bool,
     drums: &'static [u8],
+    pub master: MasterBus,
 }
 
 impl Song {
     pub fn new() -> Song {
-        Song {
+        lock_sample_rate();
+        let mut song = Song {
             current_tempo: 120,
             i: 0,
+            position: 0,
+            step: 0,
+            increment_scale: 0,
             leads: [0; 4],
             bass: 0,
             first: true,
             drums: sampler_cache!(cst::DRUMS),
+            master: MasterBus::new(),
+        };
+        song.update_sample_rate();
+        song
+    }
+
+    // Moves forward by rendering and throwing away samples, e.g. to seek from a restored snapshot
+    pub fn skip(&mut self, samples: usize) {
+        for _ in 0..samples {
+            self.get_sample();
         }
     }
 
+    fn update_sample_rate(&mut self) {
+        self.step = ((SONG_SAMPLE_RATE as u64) << 16) / sample_rate() as u64;
+        self.increment_scale = (1 << 32) / sample_rate() as u64;
+    }
+
+    // How far a 16 bit phase moves each sample for a frequency
+    #[inline]
+    fn increment(&self, freq: Half) -> u16 {
+        ((freq.to_bits() as u64 * self.increment_scale) >> 32) as u16
+    }
+
     pub fn get_sample(&mut self) -> u16 {
-        let mut output: u16 = 0;
-        output = output.saturating_add(self.get_drums(self.i, cst::LENGTH) / 2);
+        let mut mix: Sample = from_dac(self.get_drums(self.i, cst::LENGTH)) / 2;
         if self.first {
-            output = output.saturating_add(self.get_bass(self.i, cst::LENGTH) / 3);
-            output = output.saturating_add(self.get_lead(self.i, cst::LENGTH) / 3);
+            mix += from_dac(self.get_bass(self.i, cst::LENGTH)) / 3;
+            mix += from_dac(self.get_lead(self.i, cst::LENGTH)) / 3;
         } else {
-            output = output.saturating_add(self.get_second_lead(self.i, cst::LENGTH) / 3);
+            mix += from_dac(self.get_second_lead(self.i, cst::LENGTH)) / 3;
         }
-        self.i += 1;
+        let output = to_dac(self.master.process(mix));
+        self.position += self.step;
+        self.i = (self.position >> 16) as usize;
         if self.i >= cst::LENGTH {
-            self.i = 0;
+            self.position -= (cst::LENGTH as u64) << 16;
+            self.i = (self.position >> 16) as usize;
             self.bass = 0;
             self.leads = [0; 4];
             self.first = !self.first;
@@ -72,7 +118,7 @@ impl Song {
     }
 
     fn get_bass(&mut self, i: usize, length: usize) -> u16 {
-        const NOTES: [u16; 8] = [
+        const NOTES: [Half; 8] = [
             note!(130.81), //C3
             note!(130.81), //C3
             note!(155.56), //Eb3
@@ -83,7 +129,7 @@ impl Song {
             note!(196.00), //G3
         ];
         let note = NOTES[i * 8 / length];
-        self.bass = self.bass.wrapping_add(note * 2);
+        self.bass = self.bass.wrapping_add(self.increment(note) * 2);
         if self.bass > 32767 {
             return (65535 - (self.bass as i32)) as u16 * 2;
         } else {
@@ -92,7 +138,7 @@ impl Song {
     }
 
     fn get_lead(&mut self, i: usize, length: usize) -> u16 {
-        const NOTES: [u16; 32] = [
+        const NOTES: [Half; 32] = [
             note!(130.81), //C3
             note!(130.81),
             note!(130.81),
@@ -126,11 +172,11 @@ impl Song {
             note!(196.00), //G3
             note!(196.00), //G3
         ];
-        let note = NOTES[i * 32 / length];
+        let increment = self.increment(NOTES[i * 32 / length]);
         let mut offset = -2;
         let mut output = 0;
         for lead in self.leads.iter_mut() {
-            *lead = lead.wrapping_add(((note * 4) as i32 + offset) as u16);
+            *lead = lead.wrapping_add(((increment * 4) as i32 + offset) as u16);
             output += *lead / 4;
             offset += 1;
         }
@@ -138,7 +184,7 @@ impl Song {
     }
 
     fn get_second_lead(&mut self, i: usize, length: usize) -> u16 {
-        const NOTES: [u16; 32] = [
+        const NOTES: [Half; 32] = [
             note!(0.0),
             note!(0.0),
             note!(196.00),
@@ -172,14 +218,26 @@ impl Song {
             note!(196.00),
             note!(0.0),
         ];
-        let note = NOTES[i * 32 / length];
+        let increment = self.increment(NOTES[i * 32 / length]);
         let mut offset = -6;
         let mut output = 0;
         for lead in self.leads.iter_mut() {
-            *lead = lead.wrapping_add(((note * 4) as i32 + offset) as u16);
+            *lead = lead.wrapping_add(((increment * 4) as i32 + offset) as u16);
             output += *lead / 4;
             offset += 3;
         }
         output
     }
 }
+
+// Where the song is, its oscillators and the master bus, so playback can jump back to a
+// saved point and sound the same as it did the first time
+impl Snapshot for Song {
+    type State = Song;
+    fn snapshot(&self) -> Song {
+        self.clone()
+    }
+    fn restore(&mut self, state: &Song) {
+        self.clone_from(state);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::cst::*;
+use crate::helpers::*;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SaturationMode {
+    // Hard clip at -1 and 1
+    Off,
+    #[default]
+    Tanh,
+    SoftClip,
+    Asymmetric,
+}
+
+// The last stage before the dac, so loud sections distort instead of clipping
+#[derive(Debug, Clone, Copy)]
+pub struct MasterBus {
+    mode: SaturationMode,
+    drive: Sample,
+    bias: Sample,
+    level: Sample,
+}
+
+impl Default for MasterBus {
+    fn default() -> Self {
+        MasterBus::new()
+    }
+}
+
+impl MasterBus {
+    pub fn new() -> MasterBus {
+        MasterBus {
+            mode: SaturationMode::Tanh,
+            drive: s!(1),
+            bias: sf!(0.2),
+            level: s!(1),
+        }
+    }
+
+    #[inline]
+    pub fn process(&self, input: Sample) -> Sample {
+        let x = input.saturating_mul(self.drive);
+        let y = match self.mode {
+            SaturationMode::Off => x.clamp(-s!(1), s!(1)),
+            SaturationMode::Tanh => x.tanh(),
+            SaturationMode::SoftClip => x.soft_clip(),
+            SaturationMode::Asymmetric => x.asymmetric(self.bias),
+        };
+        y * self.level
+    }
+
+    pub fn run(&self, buffer: &mut [Sample]) {
+        for s in buffer.iter_mut() {
+            *s = self.process(*s);
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: SaturationMode) {
+        self.mode = mode;
+    }
+    pub fn get_mode(&self) -> SaturationMode {
+        self.mode
+    }
+    pub fn set_drive(&mut self, drive: Sample) {
+        self.drive = drive;
+    }
+    pub fn get_drive(&self) -> Sample {
+        self.drive
+    }
+    pub fn set_bias(&mut self, bias: Sample) {
+        self.bias = bias;
+    }
+    pub fn get_bias(&self) -> Sample {
+        self.bias
+    }
+    pub fn set_level(&mut self, level: Sample) {
+        self.level = level;
+    }
+    pub fn get_level(&self) -> Sample {
+        self.level
+    }
+}
+
+// Convert between the dac's unsigned 16 bit format and -1 to 1
+#[inline]
+pub fn from_dac(value: u16) -> Sample {
+    Sample::from_bits((value as i32 - 0x8000) << (Sample::FRAC_BITS - 15))
+}
+
+#[inline]
+pub fn to_dac(sample: Sample) -> u16 {
+    ((sample.to_bits() >> (Sample::FRAC_BITS - 15)) + 0x8000).clamp(0, 0xFFFF) as u16
+}
@@ -0,0 +1,180 @@
+use crate::cst::*;
+use crate::device::DeviceParameters;
+use crate::helpers::*;
+
+pub const MOD_SLOTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ModSource {
+    #[default]
+    None,
+    Lfo1,
+    Lfo2,
+    Envelope1,
+    Envelope2,
+    Velocity,
+    ModWheel,
+    Note,
+}
+
+// The current value of every modulation source, filled in by whoever is rendering.
+// Lfos are bipolar (-1 to 1), everything else runs from 0 to 1.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModSources {
+    pub lfo: [Sample; 2],
+    pub envelope: [Sample; 2],
+    pub velocity: Sample,
+    pub mod_wheel: Sample,
+    pub note: Sample,
+}
+
+impl ModSources {
+    #[inline]
+    pub fn get(&self, source: ModSource) -> Sample {
+        match source {
+            ModSource::None => s!(0),
+            ModSource::Lfo1 => self.lfo[0],
+            ModSource::Lfo2 => self.lfo[1],
+            ModSource::Envelope1 => self.envelope[0],
+            ModSource::Envelope2 => self.envelope[1],
+            ModSource::Velocity => self.velocity,
+            ModSource::ModWheel => self.mod_wheel,
+            ModSource::Note => self.note,
+        }
+    }
+    pub fn set_velocity(&mut self, velocity: u32) {
+        self.velocity = Sample::from_num(velocity.min(127)) / 127;
+    }
+    pub fn set_note(&mut self, note: Note) {
+        self.note = Sample::from_num(*note / 128);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ModSlot<P: Copy> {
+    pub source: ModSource,
+    pub destination: Option<P>,
+    // Added to the destination in Param space (0 to 1) at full source level
+    pub amount: Sample,
+}
+
+impl<P: Copy> Default for ModSlot<P> {
+    fn default() -> Self {
+        ModSlot {
+            source: ModSource::None,
+            destination: None,
+            amount: s!(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ModMatrix<P: Copy> {
+    pub slots: [ModSlot<P>; MOD_SLOTS],
+}
+
+impl<P: Copy> Default for ModMatrix<P> {
+    fn default() -> Self {
+        ModMatrix {
+            slots: [Default::default(); MOD_SLOTS],
+        }
+    }
+}
+
+impl<P: Copy> ModMatrix<P> {
+    pub fn set_slot(&mut self, slot: usize, source: ModSource, destination: P, amount: Sample) {
+        self.slots[slot] = ModSlot {
+            source,
+            destination: Some(destination),
+            amount,
+        };
+    }
+    pub fn clear_slot(&mut self, slot: usize) {
+        self.slots[slot] = Default::default();
+    }
+    pub fn clear(&mut self) {
+        for slot in self.slots.iter_mut() {
+            *slot = Default::default();
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.slots
+            .iter()
+            .all(|s| s.source == ModSource::None || s.destination.is_none())
+    }
+}
+
+impl<P: DeviceParameters> ModMatrix<P> {
+    // The parameter ty from the chunk base, with every slot routed to it added on
+    pub fn modulated(&self, base: &[i32], ty: P, sources: &ModSources) -> Param {
+        let mut value: Sample = *ty.from_chunk::<Param>(base[ty.index()]);
+        for slot in self.slots.iter() {
+            if let Some(destination) = slot.destination {
+                if destination.index() == ty.index() {
+                    value += slot.amount * sources.get(slot.source);
+                }
+            }
+        }
+        value.clamp(s!(0), s!(1)).into()
+    }
+    // Writes every routed parameter into chunk, modulated from base
+    pub fn apply(&self, base: &[i32], chunk: &mut [i32], sources: &ModSources) {
+        for slot in self.slots.iter() {
+            if let Some(destination) = slot.destination {
+                let value = self.modulated(base, destination, sources);
+                chunk[destination.index()] = destination.to_chunk(value);
+            }
+        }
+    }
+}
+
+// A sine lfo for feeding ModSources::lfo
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lfo {
+    phase: Sample,
+    freq: VibratoFreq,
+    delta: Sample,
+}
+
+impl Lfo {
+    pub fn set_freq(&mut self, freq: VibratoFreq) {
+        self.freq = freq;
+        self.delta = *freq / (sample_rate() as i32);
+    }
+    pub fn get_freq(&self) -> VibratoFreq {
+        self.freq
+    }
+    pub fn set_phase(&mut self, phase: Sample) {
+        self.phase = phase.frac();
+    }
+    pub fn get_phase(&self) -> Sample {
+        self.phase
+    }
+    // The current value, between -1 and 1
+    #[inline]
+    pub fn value(&self) -> Sample {
+        (self.phase.sin() - s!(1)) * s!(2) - s!(1)
+    }
+    // Returns the current value, then advances by a sample
+    #[inline]
+    pub fn next(&mut self) -> Sample {
+        let value = self.value();
+        self.phase = self.phase.wrapping_add(self.delta).frac();
+        value
+    }
+    pub fn advance(&mut self, samples: usize) {
+        self.phase = self
+            .phase
+            .wrapping_add(self.delta.wrapping_mul_int(samples as i32))
+            .frac();
+    }
+    // Locks the lfo to the song, cycles_per_beat cycles a beat at tempo bpm, with the phase
+    // taken from the song position so it lines up however playback got there
+    pub fn sync(&mut self, song_position: usize, tempo: u32, cycles_per_beat: Sample) {
+        let cycles = cycles_per_beat.to_bits().max(0) as i128 * tempo as i128;
+        let freq = (cycles / 60).min(i32::MAX as i128) as i32;
+        self.set_freq(Sample::from_bits(freq).into());
+        let phase = song_position as i128 * cycles / (60 * sample_rate() as i128);
+        self.phase = Sample::from_bits((phase & ((1 << Sample::FRAC_BITS) - 1)) as i32);
+    }
+}
@@ -0,0 +1,200 @@
+use crate::cst::*;
+use crate::helpers::*;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+}
+
+// Smooths out a step of height -2 at t = 0, for phase t moving by dt a sample
+#[inline]
+fn poly_blep(t: Sample, dt: Sample) -> Sample {
+    if t < dt {
+        let x = t / dt;
+        x + x - x * x - s!(1)
+    } else if t > s!(1) - dt {
+        let x = (t - s!(1)) / dt;
+        x * x + x + x + s!(1)
+    } else {
+        s!(0)
+    }
+}
+
+#[inline]
+fn naive_triangle(t: Sample) -> Sample {
+    s!(1) - (t - sf!(0.5)).abs() * s!(4)
+}
+
+// Converts a frequency into how far the phase moves each sample
+#[inline]
+pub fn freq_to_delta(freq: Freq) -> Sample {
+    let bits =
+        ((freq.to_bits() as i64) << (Sample::FRAC_BITS - Half::FRAC_BITS)) / sample_rate() as i64;
+    Sample::from_bits(bits.clamp(0, s!(1).to_bits() as i64 / 2) as i32)
+}
+
+// freq_to_delta without the divide, for pitches that move every sample. It reads the
+// sample rate when it's made, so make one per block rather than per sample.
+#[derive(Debug, Clone, Copy)]
+pub struct DeltaScale(u64);
+
+impl Default for DeltaScale {
+    fn default() -> Self {
+        DeltaScale::new()
+    }
+}
+
+impl DeltaScale {
+    pub fn new() -> DeltaScale {
+        DeltaScale((1 << (32 + Sample::FRAC_BITS - Half::FRAC_BITS)) / sample_rate() as u64)
+    }
+
+    #[inline]
+    pub fn delta(self, freq: Freq) -> Sample {
+        let bits = (freq.to_bits().max(0) as u64 * self.0) >> 32;
+        Sample::from_bits(bits.min(s!(1).to_bits() as u64 / 2) as i32)
+    }
+}
+
+// A band-limited oscillator, using polyBLEPs to round off the edges of the naive
+// waveforms so high notes don't alias. Output is between -1 and 1.
+#[derive(Debug, Clone, Copy)]
+pub struct Oscillator {
+    waveform: Waveform,
+    phase: Sample,
+    delta: Sample,
+    pulse_width: Sample,
+    triangle: Sample,
+}
+
+impl Default for Oscillator {
+    fn default() -> Self {
+        Oscillator::new()
+    }
+}
+
+impl Oscillator {
+    pub fn new() -> Oscillator {
+        Oscillator {
+            waveform: Waveform::Sine,
+            phase: s!(0),
+            delta: s!(0),
+            pulse_width: sf!(0.5),
+            triangle: -s!(1),
+        }
+    }
+
+    // The waveform without any band-limiting, for phase t
+    fn naive(&self, t: Sample) -> Sample {
+        match self.waveform {
+            Waveform::Sine => (t.sin() - s!(1)) * s!(2) - s!(1),
+            Waveform::Saw => t + t - s!(1),
+            Waveform::Square => {
+                if t < self.pulse_width {
+                    s!(1)
+                } else {
+                    -s!(1)
+                }
+            }
+            Waveform::Triangle => naive_triangle(t),
+        }
+    }
+
+    fn value(&mut self) -> Sample {
+        let t = self.phase;
+        let dt = self.delta;
+        match self.waveform {
+            Waveform::Sine => self.naive(t),
+            Waveform::Saw => self.naive(t) - poly_blep(t, dt),
+            Waveform::Square => {
+                self.naive(t) + poly_blep(t, dt)
+                    - poly_blep(t.wrapping_sub(self.pulse_width).frac(), dt)
+            }
+            Waveform::Triangle => {
+                // Integrate a band-limited square, leaking a little to stop it drifting.
+                // The leak scales with dt like the step does, so it's the same per cycle
+                // at every pitch rather than flattening low notes
+                let square = s!(1) + poly_blep(t, dt)
+                    - if t < sf!(0.5) { s!(0) } else { s!(2) }
+                    - poly_blep(t.wrapping_sub(sf!(0.5)).frac(), dt);
+                let step = dt * s!(4);
+                self.triangle += square * step;
+                self.triangle -= self.triangle * step * sf!(0.0125);
+                self.triangle
+            }
+        }
+    }
+
+    // Returns the current output, then advances by a sample
+    #[inline]
+    pub fn next(&mut self) -> Sample {
+        let value = self.value();
+        self.phase = self.phase.wrapping_add(self.delta).frac();
+        value
+    }
+
+    // Hard sync, restarting this oscillator whenever master wraps around
+    pub fn next_synced(&mut self, master: &mut Oscillator) -> Sample {
+        let before = master.phase;
+        master.next();
+        if master.phase >= before || master.delta == 0 {
+            return self.next();
+        }
+        // How far through this sample master wrapped
+        let past = master.phase / master.delta;
+        let old = self.naive(self.phase);
+        self.phase = (past * self.delta).frac();
+        let new = self.naive(self.phase);
+        if self.waveform == Waveform::Triangle {
+            self.triangle = new;
+        }
+        // The jump from old to new is a step, so smooth it the same way as the wave's own edges
+        let value = self.value() + (new - old) * sf!(0.5) * poly_blep(self.phase, self.delta);
+        self.phase = self.phase.wrapping_add(self.delta).frac();
+        value
+    }
+
+    pub fn run(&mut self, buffer: &mut [Sample]) {
+        for s in buffer.iter_mut() {
+            *s = self.next();
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.set_phase(s!(0));
+    }
+    pub fn set_freq(&mut self, freq: Freq) {
+        self.delta = freq_to_delta(freq);
+    }
+    pub fn set_delta(&mut self, delta: Sample) {
+        self.delta = delta.clamp(s!(0), sf!(0.5));
+    }
+    pub fn get_delta(&self) -> Sample {
+        self.delta
+    }
+    pub fn set_phase(&mut self, phase: Sample) {
+        self.phase = phase.frac();
+        // Start the triangle's integrator where the wave should be, so it has no dc offset
+        self.triangle = naive_triangle(self.phase);
+    }
+    pub fn get_phase(&self) -> Sample {
+        self.phase
+    }
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+    pub fn get_waveform(&self) -> Waveform {
+        self.waveform
+    }
+    // Only used by the square wave, between 0 and 1
+    pub fn set_pulse_width(&mut self, pulse_width: Sample) {
+        self.pulse_width = pulse_width.clamp(sf!(0.01), sf!(0.99));
+    }
+    pub fn get_pulse_width(&self) -> Sample {
+        self.pulse_width
+    }
+}
@@ -1,45 +1,62 @@
+use crate::cst::*;
+use crate::device::*;
 use crate::envelope::Envelope;
-use pico_donk_proc_macro::synth_device;
-
-synth_device!(
-#[derive(Copy, Clone)]
-pub struct FalconParameters {
-    Osc1Waveform: Param,
-    Osc1RatioCoarse: Param,
-    Osc1RatioFine: Param,
-    Osc1Feedback: Param,
-    Osc1FeedForward: Param,
-
-    Osc1Attack: EnvValue,
-    Osc1Decay: EnvValue,
-    Osc1Sustain: Param,
-    Osc1Release: EnvValue,
-
-    Osc2Waveform: Param,
-    Osc2RatioCoarse: Param,
-    Osc2RatioFine: Param,
-    Osc2Feedback: Param,
-
-    Osc2Attack: EnvValue,
-    Osc2Decay: EnvValue,
-    Osc2Sustain: Param,
-    Osc2Release: EnvValue,
-
-    MasterLevel: Param,
-
-    PitchAttack: EnvValue,
-    PitchDecay: EnvValue,
-    PitchSustain: Param,
-    PitchRelease: EnvValue,
-    PitchEnvAmt1: FalconEnvAmount,
-    PitchEnvAmt2: FalconEnvAmount,
+use crate::helpers::*;
+use crate::modulation::*;
+use crate::oscillator::DeltaScale;
+use crate::tuning::*;
+use pico_donk_proc_macro::{synth, synth_voice, DeviceParameters};
+
+#[derive(Copy, Clone, Debug, DeviceParameters)]
+pub enum FalconParameters {
+    Osc1Waveform,
+    Osc1RatioCoarse,
+    Osc1RatioFine,
+    Osc1Feedback,
+    Osc1FeedForward,
+
+    #[param(EnvValue)]
+    Osc1Attack,
+    #[param(EnvValue)]
+    Osc1Decay,
+    Osc1Sustain,
+    #[param(EnvValue)]
+    Osc1Release,
+
+    Osc2Waveform,
+    Osc2RatioCoarse,
+    Osc2RatioFine,
+    Osc2Feedback,
+
+    #[param(EnvValue)]
+    Osc2Attack,
+    #[param(EnvValue)]
+    Osc2Decay,
+    Osc2Sustain,
+    #[param(EnvValue)]
+    Osc2Release,
+
+    MasterLevel,
+
+    #[param(EnvValue)]
+    PitchAttack,
+    #[param(EnvValue)]
+    PitchDecay,
+    PitchSustain,
+    #[param(EnvValue)]
+    PitchRelease,
+    #[param(FalconEnvAmount)]
+    PitchEnvAmt1,
+    #[param(FalconEnvAmount)]
+    PitchEnvAmt2,
 }
 
+#[synth(voice = FalconVoice, params = FalconParameters)]
 #[derive(Debug, Default)]
 pub struct Falcon {}
 
 impl Falcon {
-    fn new() -> Self {
+    pub fn new() -> Self {
         let mut falcon: Falcon = Default::default();
         value!(falcon, FalconParameters::Osc1Waveform) = s!(0).to_bits();
         value!(falcon, FalconParameters::Osc1RatioCoarse) = s!(0).to_bits();
@@ -47,27 +64,27 @@ impl Falcon {
         value!(falcon, FalconParameters::Osc1Feedback) = s!(0).to_bits();
         value!(falcon, FalconParameters::Osc1FeedForward) = s!(0).to_bits();
 
-        value!(falcon, FalconParameters::Osc1Attack) = s!(1).to_bits();
-        value!(falcon, FalconParameters::Osc1Decay) = s!(1).to_bits();
+        value!(falcon, FalconParameters::Osc1Attack) = EnvValue::from(h!(1)).to_chunk();
+        value!(falcon, FalconParameters::Osc1Decay) = EnvValue::from(h!(1)).to_chunk();
         value!(falcon, FalconParameters::Osc1Sustain) = s!(1).to_bits();
-        value!(falcon, FalconParameters::Osc1Release) = s!(1).to_bits();
+        value!(falcon, FalconParameters::Osc1Release) = EnvValue::from(h!(1)).to_chunk();
 
         value!(falcon, FalconParameters::Osc2Waveform) = s!(0).to_bits();
         value!(falcon, FalconParameters::Osc2RatioCoarse) = s!(0).to_bits();
         value!(falcon, FalconParameters::Osc2RatioFine) = sf!(0.5).to_bits();
         value!(falcon, FalconParameters::Osc2Feedback) = s!(0).to_bits();
 
-        value!(falcon, FalconParameters::Osc1Attack) = s!(1).to_bits();
-        value!(falcon, FalconParameters::Osc1Decay) = s!(5).to_bits();
-        value!(falcon, FalconParameters::Osc1Sustain) = sf!(0.75).to_bits();
-        value!(falcon, FalconParameters::Osc1Release) = sf!(1.5).to_bits();
+        value!(falcon, FalconParameters::Osc2Attack) = EnvValue::from(h!(1)).to_chunk();
+        value!(falcon, FalconParameters::Osc2Decay) = EnvValue::from(h!(5)).to_chunk();
+        value!(falcon, FalconParameters::Osc2Sustain) = sf!(0.75).to_bits();
+        value!(falcon, FalconParameters::Osc2Release) = EnvValue::from(hf!(1.5)).to_chunk();
 
         value!(falcon, FalconParameters::MasterLevel) = sf!(0.8).to_bits();
 
-        value!(falcon, FalconParameters::PitchAttack) = s!(1).to_bits();
-        value!(falcon, FalconParameters::PitchDecay) = s!(5).to_bits();
+        value!(falcon, FalconParameters::PitchAttack) = EnvValue::from(h!(1)).to_chunk();
+        value!(falcon, FalconParameters::PitchDecay) = EnvValue::from(h!(5)).to_chunk();
         value!(falcon, FalconParameters::PitchSustain) = sf!(0.5).to_bits();
-        value!(falcon, FalconParameters::PitchRelease) = sf!(1.5).to_bits();
+        value!(falcon, FalconParameters::PitchRelease) = EnvValue::from(hf!(1.5)).to_chunk();
         value!(falcon, FalconParameters::PitchEnvAmt1) = s!(0).to_bits();
         value!(falcon, FalconParameters::PitchEnvAmt2) = s!(0).to_bits();
         defaults!(falcon);
@@ -75,6 +92,7 @@ impl Falcon {
     }
 }
 
+#[synth_voice]
 #[derive(Debug, Default, Clone)]
 pub struct FalconVoice {
     osc1Env: Envelope,
@@ -88,35 +106,181 @@ pub struct FalconVoice {
     osc2Output: Sample,
 }
 
-impl SynthDevice for Falcon {}
-
+#[synth_voice(params = FalconParameters)]
 impl Voice for FalconVoice {
-    fn note_on(&mut self) {
-        self.osc1Phase = Sample::rand();
+    fn on_note_on(&mut self, params: ParamView<Self::Param>, _velocity: u32) {
+        self.osc1Phase = Sample::rand(&mut self.rng);
         self.osc2Phase = self.osc1Phase;
-        self.osc1Env.set_attack(Sample::from_bits(value!(self.parameters, FalconParameters::Osc1Attack)));
-        /*self.osc1Env.decay = falcon->osc1Decay;
-        self.osc1Env.Sustain = falcon->osc1Sustain;
-        self.osc1Env.Release = falcon->osc1Release;
-        self.osc1Env.Trigger();
-        self.osc2Env.Attack = falcon->osc2Attack;
-        self.osc2Env.Decay = falcon->osc2Decay;
-        self.osc2Env.Sustain = falcon->osc2Sustain;
-        self.osc2Env.Release = falcon->osc2Release;
-        self.osc2Env.Trigger();
-
-        self.pitchEnv.Attack = falcon->pitchAttack;
-        self.pitchEnv.Decay = falcon->pitchDecay;
-        self.pitchEnv.Sustain = falcon->pitchSustain;
-        self.pitchEnv.Release = falcon->pitchRelease;
-        self.pitchEnv.Trigger();
-
-        osc1Output = osc2Output = 0.0;*/
+
+        self.osc1Env
+            .set_attack(params.value(FalconParameters::Osc1Attack));
+        self.osc1Env
+            .set_decay(params.value(FalconParameters::Osc1Decay));
+        self.osc1Env
+            .set_sustain(*params.value::<Param>(FalconParameters::Osc1Sustain));
+        self.osc1Env
+            .set_release(params.value(FalconParameters::Osc1Release));
+        self.osc1Env.trigger();
+        self.osc2Env
+            .set_attack(params.value(FalconParameters::Osc2Attack));
+        self.osc2Env
+            .set_decay(params.value(FalconParameters::Osc2Decay));
+        self.osc2Env
+            .set_sustain(*params.value::<Param>(FalconParameters::Osc2Sustain));
+        self.osc2Env
+            .set_release(params.value(FalconParameters::Osc2Release));
+        self.osc2Env.trigger();
+
+        self.pitchEnv
+            .set_attack(params.value(FalconParameters::PitchAttack));
+        self.pitchEnv
+            .set_decay(params.value(FalconParameters::PitchDecay));
+        self.pitchEnv
+            .set_sustain(*params.value::<Param>(FalconParameters::PitchSustain));
+        self.pitchEnv
+            .set_release(params.value(FalconParameters::PitchRelease));
+        self.pitchEnv.trigger();
+
+        self.osc1Output = s!(0);
+        self.osc2Output = s!(0);
+    }
+    fn note_off(&mut self, _params: ParamView<Self::Param>) {
+        self.osc1Env.off();
+        self.osc2Env.off();
+        self.pitchEnv.off();
+    }
+    fn mod_envelopes(&self) -> [Sample; 2] {
+        [self.osc1Env.get_value(), self.osc2Env.get_value()]
+    }
+    // Osc2 is what's heard, so the voice is done once its release has run out
+    fn is_finished(&self) -> bool {
+        self.osc2Env.is_finished()
     }
-    fn note_off(&mut self) { }
-    fn run(&self, song_position: usize, buffer: &mut [Sample]) -> Result<usize, DeviceError> {
-        for i in buffer {
-            *i += s!(1);
+    // Two operator FM, osc1 modulates osc2 by FeedForward and each feeds back into itself
+    fn run(
+        &mut self,
+        params: ParamView<Self::Param>,
+        song_position: usize,
+        buffer: &mut [Sample],
+    ) -> Result<usize, DeviceError> {
+        let ratio1 = ratio(
+            *params.value::<Param>(FalconParameters::Osc1RatioCoarse),
+            *params.value::<Param>(FalconParameters::Osc1RatioFine),
+        );
+        let ratio2 = ratio(
+            *params.value::<Param>(FalconParameters::Osc2RatioCoarse),
+            *params.value::<Param>(FalconParameters::Osc2RatioFine),
+        );
+        let feedback1 = *params.value::<Param>(FalconParameters::Osc1Feedback);
+        let feedback2 = *params.value::<Param>(FalconParameters::Osc2Feedback);
+        let feed_forward = *params.value::<Param>(FalconParameters::Osc1FeedForward);
+        let pitch_amount1 = *params.value::<FalconEnvAmount>(FalconParameters::PitchEnvAmt1);
+        let pitch_amount2 = *params.value::<FalconEnvAmount>(FalconParameters::PitchEnvAmt2);
+        let level = *params.value::<Param>(FalconParameters::MasterLevel);
+        // The pitch moves every sample with slides and the pitch envelope, so this keeps the
+        // divide in turning it into a phase step out of the loop
+        let scale = DeltaScale::new();
+
+        for out in buffer.iter_mut() {
+            let note = *self.get_note() + Half::from_num(*self.detune);
+            let pitch = self.pitchEnv.get_value();
+            let freq1 = self
+                .tuning
+                .freq((note + Half::from_num(pitch * pitch_amount1)).into());
+            let freq2 = self
+                .tuning
+                .freq((note + Half::from_num(pitch * pitch_amount2)).into());
+
+            self.osc1Output =
+                sine(self.osc1Phase + self.osc1Output * feedback1) * self.osc1Env.get_value();
+            self.osc2Output =
+                sine(self.osc2Phase + self.osc1Output * feed_forward + self.osc2Output * feedback2)
+                    * self.osc2Env.get_value();
+            *out += self.osc2Output * level;
+
+            self.osc1Phase = self
+                .osc1Phase
+                .wrapping_add(scale.delta(freq1) * ratio1)
+                .frac();
+            self.osc2Phase = self
+                .osc2Phase
+                .wrapping_add(scale.delta(freq2) * ratio2)
+                .frac();
+            self.osc1Env.next();
+            self.osc2Env.next();
+            self.pitchEnv.next();
         }
-        Ok(0) }}
-);
+        Ok(song_position + buffer.len())
+    }
+}
+
+// Coarse picks a whole number ratio from 1 to 16, fine moves it by up to half either way
+#[inline]
+fn ratio(coarse: Sample, fine: Sample) -> Sample {
+    s!(1) + (coarse * s!(15)).floor() + fine - sf!(0.5)
+}
+
+// sin() is offset to between 1 and 2 for the lookup table, this is between -1 and 1
+#[inline]
+fn sine(phase: Sample) -> Sample {
+    (phase.sin() - sf!(1.5)) * s!(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflowing_events_are_reported() {
+        let mut falcon = Falcon::new();
+        let capacity = Falcon::MAX_EVENTS;
+        for i in 0..capacity + 2 {
+            falcon.note_on(h!(60).into(), 100, i);
+        }
+        let mut buffer = [s!(0); 16];
+        assert_eq!(falcon.run(0, &mut buffer), Ok(16));
+        let error = falcon.take_dropped_events().unwrap_err();
+        assert_eq!(error.kind(), DeviceErrorKind::EventOverflow { dropped: 2 });
+        assert_eq!(error.name(), "Falcon");
+        // Taking them resets the count
+        assert_eq!(falcon.take_dropped_events(), Ok(()));
+    }
+
+    #[test]
+    fn unison_voices_start_in_different_places() {
+        let mut falcon = Falcon::new();
+        falcon.set_voices_unisono(2.into());
+        falcon.note_on(h!(60).into(), 100, 0);
+        let mut buffer = [s!(0); 1];
+        falcon.run(0, &mut buffer).unwrap();
+        assert!(falcon.voices[0].is_on() && falcon.voices[1].is_on());
+        assert_ne!(falcon.voices[0].osc1Phase, falcon.voices[1].osc1Phase);
+    }
+
+    #[test]
+    fn routed_mod_slots_change_the_output() {
+        let render = |falcon: &mut Falcon| {
+            falcon.note_on(h!(60).into(), 100, 0);
+            let mut buffer = [s!(0); 256];
+            falcon.run(0, &mut buffer).unwrap();
+            buffer
+        };
+        let plain = render(&mut Falcon::new());
+
+        let mut falcon = Falcon::new();
+        falcon.set_mod_wheel(s!(1));
+        falcon.get_mod_matrix_mut().set_slot(
+            0,
+            ModSource::ModWheel,
+            FalconParameters::MasterLevel,
+            -sf!(0.5),
+        );
+        let modulated = render(&mut falcon);
+
+        assert_ne!(plain, modulated);
+        // Summed as bits, as 256 samples could be more than a Sample holds
+        let level =
+            |buffer: &[Sample]| buffer.iter().map(|s| s.abs().to_bits() as i64).sum::<i64>();
+        assert!(level(&modulated) < level(&plain));
+    }
+}
@@ -0,0 +1,33 @@
+use crate::cst::*;
+use crate::helpers::*;
+use pico_donk_proc_macro::tuning;
+
+// The frequency of every midi note. Build these at compile time with tuning!(),
+// which can read Scala .scl/.kbm files.
+#[derive(Debug)]
+pub struct Tuning {
+    pub table: [Half; NOTE_TAB_SIZE],
+}
+
+pub const TWELVE_TET: Tuning = Tuning { table: NOTE_TAB };
+
+tuning!(TWELVE_TET_432, reference = 432.0);
+
+impl Tuning {
+    pub fn freq(&self, note: Note) -> Freq {
+        let index = note.to_num::<i32>().clamp(0, NOTE_TAB_SIZE as i32 - 2) as usize;
+        if note.frac() == 0i32 {
+            self.table[note.to_num::<i32>().clamp(0, NOTE_TAB_SIZE as i32 - 1) as usize].into()
+        } else {
+            note.frac()
+                .lerp(self.table[index], self.table[index + 1])
+                .into()
+        }
+    }
+}
+
+impl Default for &'static Tuning {
+    fn default() -> Self {
+        &TWELVE_TET
+    }
+}
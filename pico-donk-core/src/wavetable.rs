@@ -0,0 +1,142 @@
+use crate::cst::*;
+use crate::helpers::*;
+use crate::oscillator::freq_to_delta;
+
+// A set of single-cycle frames, each 2^frame_log2_size samples long plus a copy of the
+// first sample on the end so TableLookup::lookup can interpolate across the wrap.
+#[derive(Debug, Clone, Copy)]
+pub struct Wavetable {
+    data: &'static [Sample],
+    frame_log2_size: usize,
+}
+
+impl Wavetable {
+    // Panics (at compile time for consts) if the frames are longer than TableLookup can
+    // index, or if data isn't a whole, non-zero number of frames
+    pub const fn new(data: &'static [Sample], frame_log2_size: usize) -> Wavetable {
+        assert!(
+            frame_log2_size <= 12,
+            "wavetable frames can be at most 2^12 samples"
+        );
+        let frame_len = (1 << frame_log2_size) + 1;
+        assert!(
+            !data.is_empty() && data.len() % frame_len == 0,
+            "wavetable data must be whole frames of 2^frame_log2_size + 1 samples"
+        );
+        Wavetable {
+            data,
+            frame_log2_size,
+        }
+    }
+    #[inline]
+    pub const fn frame_len(&self) -> usize {
+        (1 << self.frame_log2_size) + 1
+    }
+    #[inline]
+    pub const fn frames(&self) -> usize {
+        self.data.len() / self.frame_len()
+    }
+    #[inline]
+    pub fn frame(&self, n: usize) -> &'static [Sample] {
+        let len = self.frame_len();
+        &self.data[n * len..(n + 1) * len]
+    }
+}
+
+// Turns raw signed 16 bit little endian frames (e.g. from include_bytes!, like cst::DRUMS)
+// into the layout Wavetable wants, at compile time.
+pub const fn wavetable_from_raw<const FRAME_LOG2_SIZE: usize, const FRAMES: usize>(
+    bytes: &[u8],
+) -> [Sample; FRAMES * ((1 << FRAME_LOG2_SIZE) + 1)]
+where
+    [(); FRAMES * ((1 << FRAME_LOG2_SIZE) + 1)]:,
+{
+    let size = 1 << FRAME_LOG2_SIZE;
+    let mut table = [s!(0); FRAMES * ((1 << FRAME_LOG2_SIZE) + 1)];
+    let mut frame = 0;
+    while frame < FRAMES {
+        let mut i = 0;
+        while i <= size {
+            // The extra sample at the end of each frame wraps round to the start
+            let j = (frame * size + (i % size)) * 2;
+            let value = (bytes[j] as u16 | (bytes[j + 1] as u16) << 8) as i16;
+            table[frame * (size + 1) + i] =
+                Sample::from_bits((value as i32) << (Sample::FRAC_BITS - 15));
+            i += 1;
+        }
+        frame += 1;
+    }
+    table
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WavetableOscillator {
+    table: &'static Wavetable,
+    phase: Sample,
+    delta: Sample,
+    // Where we are between the first (0) and last (1) frame
+    position: Sample,
+}
+
+impl WavetableOscillator {
+    pub fn new(table: &'static Wavetable) -> WavetableOscillator {
+        WavetableOscillator {
+            table,
+            phase: s!(0),
+            delta: s!(0),
+            position: s!(0),
+        }
+    }
+
+    fn value(&self) -> Sample {
+        let frames = self.table.frames();
+        let log2_size = self.table.frame_log2_size;
+        if frames < 2 {
+            return self.phase.lookup(self.table.frame(0), log2_size);
+        }
+        // Done on the raw bits in i64, as position * frames won't fit in a Sample for big tables
+        let position =
+            self.position.to_bits().clamp(0, s!(1).to_bits()) as i64 * (frames as i64 - 1);
+        let index = ((position >> Sample::FRAC_BITS) as usize).min(frames - 2);
+        let mix = Sample::from_bits((position - ((index as i64) << Sample::FRAC_BITS)) as i32);
+        let left = self.phase.lookup(self.table.frame(index), log2_size);
+        let right = self.phase.lookup(self.table.frame(index + 1), log2_size);
+        left + (right - left) * mix
+    }
+
+    // Returns the current output, then advances by a sample
+    #[inline]
+    pub fn next(&mut self) -> Sample {
+        let value = self.value();
+        self.phase = self.phase.wrapping_add(self.delta).frac();
+        value
+    }
+
+    pub fn run(&mut self, buffer: &mut [Sample]) {
+        for s in buffer.iter_mut() {
+            *s = self.next();
+        }
+    }
+
+    pub fn set_table(&mut self, table: &'static Wavetable) {
+        self.table = table;
+    }
+    pub fn get_table(&self) -> &'static Wavetable {
+        self.table
+    }
+    pub fn set_freq(&mut self, freq: Freq) {
+        self.delta = freq_to_delta(freq);
+    }
+    pub fn set_phase(&mut self, phase: Sample) {
+        self.phase = phase.frac();
+    }
+    pub fn get_phase(&self) -> Sample {
+        self.phase
+    }
+    pub fn set_position(&mut self, position: Sample) {
+        self.position = position.clamp(s!(0), s!(1));
+    }
+    pub fn get_position(&self) -> Sample {
+        self.position
+    }
+}
@@ -0,0 +1,348 @@
+// Sweeps the fixed point helpers across their domains and compares them against f64,
+// to see how much each one loses and where.
+// Run with `cargo run -- accuracy`, add `--csv` for something to paste into a spreadsheet.
+// Param round trips are checked on every 64th Param, `--exhaustive` checks all of them
+// (best done with --release).
+// The float feature replaces the fixed types for the whole build, so this can't put fixed
+// and Float side by side. It compares against f64 instead, and is only meaningful without
+// --features float. Whole devices are compared against the float build by render.rs.
+
+use pico_donk_core::cst::*;
+use pico_donk_core::helpers::*;
+
+// How many points each sweep takes
+const POINTS: usize = 4096;
+// How far apart the Params checked by a round trip are, without --exhaustive
+const ROUND_TRIP_STEP: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Increasing,
+    Either,
+}
+
+pub struct Report {
+    pub name: String,
+    pub points: usize,
+    pub max_error: f64,
+    // The input that gave max_error
+    pub worst_input: f64,
+    pub rms_error: f64,
+    // How often the output stepped the wrong way, for helpers that should be monotonic
+    pub monotonicity_violations: usize,
+    // For Param mappings, the furthest get(set(x)) lands from x, in Param LSBs
+    pub round_trip_loss: Option<f64>,
+}
+
+// Compares f with reference at POINTS inputs spread evenly from `from` to `to`.
+// f gets the input already quantised, and the reference gets the same quantised value,
+// so only the error from the helper itself is counted.
+#[allow(clippy::too_many_arguments)]
+fn sweep<T: Copy>(
+    name: &str,
+    from: f64,
+    to: f64,
+    direction: Direction,
+    quantise: impl Fn(f64) -> T,
+    value: impl Fn(T) -> f64,
+    f: impl Fn(T) -> f64,
+    reference: impl Fn(f64) -> f64,
+) -> Report {
+    let mut max_error = 0.0;
+    let mut worst_input = from;
+    let mut square_error = 0.0;
+    let mut monotonicity_violations = 0;
+    let mut last: Option<f64> = None;
+    for i in 0..POINTS {
+        let input = quantise(from + (to - from) * i as f64 / (POINTS - 1) as f64);
+        let x = value(input);
+        let out = f(input);
+        let error = (out - reference(x)).abs();
+        if error > max_error {
+            max_error = error;
+            worst_input = x;
+        }
+        square_error += error * error;
+        if let Some(last) = last {
+            let wrong_way = match direction {
+                Direction::Increasing => out < last,
+                Direction::Either => false,
+            };
+            if wrong_way {
+                monotonicity_violations += 1;
+            }
+        }
+        last = Some(out);
+    }
+    Report {
+        name: name.to_owned(),
+        points: POINTS,
+        max_error,
+        worst_input,
+        rms_error: (square_error / POINTS as f64).sqrt(),
+        monotonicity_violations,
+        round_trip_loss: None,
+    }
+}
+
+fn sample(x: f64) -> Sample {
+    Sample::saturating_from_num(x)
+}
+fn half(x: f64) -> Half {
+    Half::saturating_from_num(x)
+}
+
+// sin and cos return between 1 and 2, see FAST_SIN_TAB
+fn offset_sin(x: f64) -> f64 {
+    (x * std::f64::consts::TAU).sin() * 0.5 + 1.5
+}
+fn offset_cos(x: f64) -> f64 {
+    (x * std::f64::consts::TAU).cos() * 0.5 + 1.5
+}
+
+fn sample_sweep(
+    name: &str,
+    from: f64,
+    to: f64,
+    direction: Direction,
+    f: impl Fn(Sample) -> Sample,
+    reference: impl Fn(f64) -> f64,
+) -> Report {
+    sweep(
+        name,
+        from,
+        to,
+        direction,
+        sample,
+        |x| x.to_num::<f64>(),
+        |x| f(x).to_num::<f64>(),
+        reference,
+    )
+}
+
+fn half_sweep(
+    name: &str,
+    from: f64,
+    to: f64,
+    direction: Direction,
+    f: impl Fn(Half) -> Half,
+    reference: impl Fn(f64) -> f64,
+) -> Report {
+    sweep(
+        name,
+        from,
+        to,
+        direction,
+        half,
+        |x| x.to_num::<f64>(),
+        |x| f(x).to_num::<f64>(),
+        reference,
+    )
+}
+
+// Checks a From<Param> mapping against what it's meant to be, and how well the
+// mapping back gets to the Param we started with
+fn param_sweep<T: Copy + From<Param> + Into<Param>>(
+    name: &str,
+    round_trip_step: usize,
+    value: impl Fn(T) -> f64,
+    reference: impl Fn(f64) -> f64,
+) -> Report {
+    let mut report = sweep(
+        name,
+        0.0,
+        1.0,
+        Direction::Increasing,
+        |x| Param::from(sample(x)),
+        |p| p.to_num::<f64>(),
+        |p| value(T::from(p)),
+        reference,
+    );
+    let lsb = Sample::from_bits(1).to_num::<f64>();
+    let mut loss: f64 = 0.0;
+    for bits in (0..=Sample::from_num(1).to_bits()).step_by(round_trip_step) {
+        let p = Param::from(Sample::from_bits(bits));
+        let back: Param = T::from(p).into();
+        loss = loss.max((back.to_num::<f64>() - p.to_num::<f64>()).abs() / lsb);
+    }
+    report.round_trip_loss = Some(loss);
+    report
+}
+
+pub fn reports(exhaustive: bool) -> Vec<Report> {
+    use Direction::*;
+    let step = if exhaustive { 1 } else { ROUND_TRIP_STEP };
+    vec![
+        sample_sweep("sin (Sample)", 0.0, 1.0, Either, |x| x.sin(), offset_sin),
+        half_sweep("sin (Half)", 0.0, 1.0, Either, |x| x.sin(), offset_sin),
+        sample_sweep("cos (Sample)", 0.0, 1.0, Either, |x| x.cos(), offset_cos),
+        half_sweep("cos (Half)", 0.0, 1.0, Either, |x| x.cos(), offset_cos),
+        sample_sweep(
+            "exp2 (Sample)",
+            -8.0,
+            6.0,
+            Increasing,
+            |x| x.exp2(),
+            f64::exp2,
+        ),
+        half_sweep(
+            "exp2 (Half)",
+            -8.0,
+            14.0,
+            Increasing,
+            |x| x.exp2(),
+            f64::exp2,
+        ),
+        sample_sweep(
+            "exp10 (Sample)",
+            -2.0,
+            2.0,
+            Increasing,
+            |x| x.exp10(),
+            |x| 10f64.powf(x),
+        ),
+        half_sweep(
+            "exp10 (Half)",
+            -4.0,
+            4.0,
+            Increasing,
+            |x| x.exp10(),
+            |x| 10f64.powf(x),
+        ),
+        sample_sweep(
+            "log2 (Sample)",
+            0.01,
+            127.0,
+            Increasing,
+            |x| x.log2(),
+            f64::log2,
+        ),
+        half_sweep(
+            "log2 (Half)",
+            0.01,
+            32000.0,
+            Increasing,
+            |x| x.log2(),
+            f64::log2,
+        ),
+        sample_sweep(
+            "sqrt (Sample)",
+            0.0,
+            127.0,
+            Increasing,
+            |x| x.sqrt(),
+            f64::sqrt,
+        ),
+        half_sweep(
+            "sqrt (Half)",
+            0.0,
+            32000.0,
+            Increasing,
+            |x| x.sqrt(),
+            f64::sqrt,
+        ),
+        sample_sweep(
+            "lookup (FAST_EXP_TAB)",
+            0.0,
+            1.0 - 1.0 / POINTS as f64,
+            Increasing,
+            |x| x.lookup(&FAST_EXP_TAB, FAST_EXP_TAB_LOG2_SIZE),
+            |x| x.exp2() - 1.0,
+        ),
+        sample_sweep(
+            "tanh (Sample)",
+            -8.0,
+            8.0,
+            Increasing,
+            |x| x.tanh(),
+            f64::tanh,
+        ),
+        param_sweep::<Freq>(
+            "Param -> Freq",
+            step,
+            |f| f.to_num::<f64>(),
+            |p| (p * p * 63.0 + p) / 64.0 * 19980.0 + 20.0,
+        ),
+        param_sweep::<Q>(
+            "Param -> Q",
+            step,
+            |q| q.to_num::<f64>(),
+            |p| {
+                if p < 0.5 {
+                    p * 1.32 + 0.33
+                } else {
+                    p * 22.0 - 10.0
+                }
+            },
+        ),
+        param_sweep::<SlideTime>(
+            "Param -> SlideTime",
+            step,
+            |t| t.to_num::<f64>(),
+            |p| p * 65536.0 + p * p * p * p * (480000.0 - 65536.0),
+        ),
+        param_sweep::<EnvValue>(
+            "Param -> EnvValue",
+            step,
+            |e| e.to_num::<f64>(),
+            |p| (p * p * 15.0 + p) / 16.0 * 5000.0 + 1.0,
+        ),
+        param_sweep::<VibratoFreq>(
+            "Param -> VibratoFreq",
+            step,
+            |v| v.to_num::<f64>(),
+            |p| (p * p * 31.0 + p) / 32.0 * 70.0 + 7.0,
+        ),
+        param_sweep::<FalconEnvAmount>(
+            "Param -> FalconEnvAmount",
+            step,
+            |a| a.to_num::<f64>(),
+            |p| (p - 0.5) * 72.0,
+        ),
+        param_sweep::<Note>("Param -> Note", step, |n| n.to_num::<f64>(), |p| p * 128.0),
+    ]
+}
+
+pub fn print_text(reports: &[Report]) {
+    println!(
+        "{:<26} {:>7} {:>14} {:>14} {:>14} {:>10} {:>16}",
+        "helper", "points", "max error", "at", "rms error", "monotonic", "round trip lsbs"
+    );
+    for r in reports {
+        println!(
+            "{:<26} {:>7} {:>14.3e} {:>14.6} {:>14.3e} {:>10} {:>16}",
+            r.name,
+            r.points,
+            r.max_error,
+            r.worst_input,
+            r.rms_error,
+            if r.monotonicity_violations == 0 {
+                "yes".to_owned()
+            } else {
+                format!("{} bad", r.monotonicity_violations)
+            },
+            match r.round_trip_loss {
+                Some(loss) => format!("{:.1}", loss),
+                None => "-".to_owned(),
+            }
+        );
+    }
+}
+
+pub fn print_csv(reports: &[Report]) {
+    println!(
+        "helper,points,max_error,worst_input,rms_error,monotonicity_violations,round_trip_lsbs"
+    );
+    for r in reports {
+        println!(
+            "{},{},{:e},{},{:e},{},{}",
+            r.name,
+            r.points,
+            r.max_error,
+            r.worst_input,
+            r.rms_error,
+            r.monotonicity_violations,
+            r.round_trip_loss.map(|l| l.to_string()).unwrap_or_default()
+        );
+    }
+}
@@ -1,18 +1,52 @@
 #![feature(generic_const_exprs)]
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use cpal::{ChannelCount, Sample, SampleRate};
-use pico_donk_core::cst::Half as PicoHalf;
-use pico_donk_core::cst::Sample as PicoSample;
+use pico_donk_core::cst::set_sample_rate;
 use pico_donk_core::device::Device;
 use pico_donk_core::device::SynthDevice;
-use pico_donk_core::helpers::{Exp, SinCos};
 use pico_donk_core::synth::falcon::Falcon;
 use pico_donk_core::Song;
 
+mod accuracy;
+mod render;
+
 const CHANNELS: ChannelCount = 2;
+// Used if the device can do it, otherwise we take whatever it's closest to
 const SAMPLE_RATE: SampleRate = SampleRate { 0: 48000 };
 
-fn main() -> ! {
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    if args.first().map(|a| a == "accuracy").unwrap_or(false) {
+        let reports = accuracy::reports(args.iter().any(|a| a == "--exhaustive"));
+        if args.iter().any(|a| a == "--csv") {
+            accuracy::print_csv(&reports);
+        } else {
+            accuracy::print_text(&reports);
+        }
+        return;
+    }
+    if let Some(command @ ("render" | "compare")) = args.first().map(|a| a.as_str()) {
+        let devices = if args.len() > 1 {
+            args[1..].iter().map(|a| a.as_str()).collect::<Vec<_>>()
+        } else {
+            render::DEVICES.to_vec()
+        };
+        for device in devices {
+            if command == "render" {
+                match render::render(device) {
+                    Ok(path) => println!("{}: wrote {}", device, path),
+                    Err(e) => eprintln!("{}: {}", device, e),
+                }
+            } else {
+                match render::compare(device) {
+                    Ok(comparison) => render::print_comparison(device, &comparison),
+                    Err(e) => eprintln!("{}: {}", device, e),
+                }
+            }
+        }
+        return;
+    }
+
     let host = cpal::default_host();
     let device = host
         .default_output_device()
@@ -20,13 +54,23 @@ fn main() -> ! {
     let supported_config = device
         .supported_output_configs()
         .expect("Error while querying audio configs")
-        .find(|c| {
-            c.channels() == CHANNELS
-                && c.min_sample_rate() <= SAMPLE_RATE
-                && c.max_sample_rate() >= SAMPLE_RATE
+        .filter(|c| c.channels() == CHANNELS)
+        .min_by_key(|c| {
+            if c.min_sample_rate() > SAMPLE_RATE {
+                c.min_sample_rate().0 - SAMPLE_RATE.0
+            } else if c.max_sample_rate() < SAMPLE_RATE {
+                SAMPLE_RATE.0 - c.max_sample_rate().0
+            } else {
+                0
+            }
         })
-        .expect("Could not find suitable audio config")
-        .with_sample_rate(SAMPLE_RATE);
+        .expect("Could not find suitable audio config");
+    let sample_rate = SAMPLE_RATE.clamp(
+        supported_config.min_sample_rate(),
+        supported_config.max_sample_rate(),
+    );
+    let supported_config = supported_config.with_sample_rate(sample_rate);
+    set_sample_rate(sample_rate.0);
 
     let mut song = Song::new();
 
@@ -51,34 +95,17 @@ fn main() -> ! {
 
     let mut device: Falcon = Default::default();
 
-    device.run(0, &mut buffer).unwrap();
+    let song_position = device.run(0, &mut buffer).unwrap();
 
     device.note_on(Default::default(), Default::default(), 16);
     device.note_on(Default::default(), Default::default(), 18);
 
-    device.run(0, &mut buffer).unwrap();
+    device.run(song_position, &mut buffer).unwrap();
 
     stream.play().expect("Unable to play stream");
 
+    // The stream runs on its own thread, we just need to keep it alive
     loop {
-        let mut input_text = String::new();
-        match std::io::stdin().read_line(&mut input_text) {
-            Ok(_) => match input_text.trim().parse::<PicoHalf>() {
-                Ok(sample) => {
-                    let first = ((sample.cos().to_num::<f64>() - 1.) * 2.) - 1.;
-                    let second = PicoHalf::from_num(
-                        (sample.to_num::<f64>() * (std::f64::consts::TAU)).cos(),
-                    )
-                    .to_num::<f64>();
-                    println!("{} vs {}, difference {}", first, second, second - first);
-
-                    let first = sample.exp2().to_num::<f64>();
-                    let second = PicoHalf::from_num(sample.to_num::<f64>().exp2()).to_num::<f64>();
-                    println!("{} vs {}, difference {}", first, second, second - first);
-                }
-                Err(e) => eprintln!("{}", e),
-            },
-            Err(e) => eprintln!("{}", e),
-        };
+        std::thread::park();
     }
 }
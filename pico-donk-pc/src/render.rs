@@ -0,0 +1,167 @@
+// Renders each device through a fixed phrase to a raw file, so the fixed point build can be
+// compared against the float reference. The two have to be separate binaries, so:
+//   cargo run --release -- render falcon
+//   cargo run --release --features float -- render falcon
+//   cargo run --release -- compare falcon
+// render writes <device>-fixed.raw or <device>-float.raw depending on the build, as little
+// endian f64s, and compare reads both and reports how far the fixed one is from the float one.
+
+use std::fs;
+use std::io;
+
+use pico_donk_core::cst::{Half, Sample};
+use pico_donk_core::device::{Device, SynthDevice};
+use pico_donk_core::synth::falcon::Falcon;
+use pico_donk_core::Song;
+
+pub const DEVICES: &[&str] = &["falcon", "song"];
+
+// How many samples go through run at a time
+const BLOCK: usize = 64;
+// The phrase played through synths, each note held for NOTE_LENGTH then released
+const NOTES: [i32; 8] = [48, 55, 60, 63, 67, 72, 60, 36];
+const NOTE_LENGTH: usize = 6000;
+// Left after the last note for its release to finish
+const TAIL: usize = 24000;
+// How long the song is rendered for
+const SONG_LENGTH: usize = 96000;
+
+fn build() -> &'static str {
+    if cfg!(feature = "float") {
+        "float"
+    } else {
+        "fixed"
+    }
+}
+
+fn path(device: &str, build: &str) -> String {
+    format!("{}-{}.raw", device, build)
+}
+
+// Runs device for samples, adding what it makes onto out
+fn run_for<D: Device<N>, const N: usize>(device: &mut D, samples: usize, out: &mut Vec<f64>) {
+    let mut left = samples;
+    while left > 0 {
+        let mut buffer = [Sample::default(); BLOCK];
+        let len = left.min(BLOCK);
+        let position = out.len();
+        device
+            .run(position, &mut buffer[..len])
+            .expect("device failed while rendering");
+        out.extend(buffer[..len].iter().map(|s| s.to_num::<f64>()));
+        left -= len;
+    }
+}
+
+fn render_falcon() -> Vec<f64> {
+    let mut falcon = Falcon::new();
+    let mut out = Vec::with_capacity(NOTES.len() * NOTE_LENGTH + TAIL);
+    for note in NOTES {
+        let note = Half::from_num(note).into();
+        falcon.note_on(note, 100, 0);
+        run_for(&mut falcon, NOTE_LENGTH, &mut out);
+        falcon.note_off(note, 0);
+    }
+    run_for(&mut falcon, TAIL, &mut out);
+    falcon
+        .take_dropped_events()
+        .expect("the phrase shouldn't fill the event queue");
+    out
+}
+
+fn render_song() -> Vec<f64> {
+    let mut song = Song::new();
+    (0..SONG_LENGTH)
+        .map(|_| song.get_sample() as f64 / 32768.0 - 1.0)
+        .collect()
+}
+
+// Renders device and writes it out for this build, returning where it went
+pub fn render(device: &str) -> io::Result<String> {
+    let samples = match device {
+        "falcon" => render_falcon(),
+        "song" => render_song(),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unknown device {}, expected one of {}",
+                    device,
+                    DEVICES.join(", ")
+                ),
+            ))
+        }
+    };
+    let bytes = samples
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect::<Vec<u8>>();
+    let path = path(device, build());
+    fs::write(&path, bytes)?;
+    Ok(path)
+}
+
+fn read(path: &str) -> io::Result<Vec<f64>> {
+    Ok(fs::read(path)?
+        .chunks_exact(8)
+        .map(|b| f64::from_le_bytes(b.try_into().unwrap()))
+        .collect())
+}
+
+pub struct Comparison {
+    pub samples: usize,
+    pub max_error: f64,
+    // The sample that gave max_error
+    pub worst_sample: usize,
+    pub rms_error: f64,
+    // How far the float render is above the difference between the two, in db
+    pub snr: f64,
+}
+
+// Compares the fixed render of device against the float one
+pub fn compare(device: &str) -> io::Result<Comparison> {
+    let fixed = read(&path(device, "fixed"))?;
+    let float = read(&path(device, "float"))?;
+    if fixed.len() != float.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the renders are different lengths, {} fixed and {} float samples",
+                fixed.len(),
+                float.len()
+            ),
+        ));
+    }
+    let mut comparison = Comparison {
+        samples: fixed.len(),
+        max_error: 0.0,
+        worst_sample: 0,
+        rms_error: 0.0,
+        snr: f64::INFINITY,
+    };
+    let mut signal = 0.0;
+    let mut noise = 0.0;
+    for (i, (a, b)) in fixed.iter().zip(float.iter()).enumerate() {
+        let error = (a - b).abs();
+        if error > comparison.max_error {
+            comparison.max_error = error;
+            comparison.worst_sample = i;
+        }
+        signal += b * b;
+        noise += error * error;
+    }
+    if comparison.samples > 0 {
+        comparison.rms_error = (noise / comparison.samples as f64).sqrt();
+    }
+    if noise > 0.0 {
+        comparison.snr = 10.0 * (signal / noise).log10();
+    }
+    Ok(comparison)
+}
+
+pub fn print_comparison(device: &str, c: &Comparison) {
+    println!(
+        "{}: {} samples, max error {:.3e} at sample {}, rms error {:.3e}, snr {:.1} db",
+        device, c.samples, c.max_error, c.worst_sample, c.rms_error, c.snr
+    );
+}
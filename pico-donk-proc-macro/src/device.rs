@@ -1,17 +1,184 @@
-use core::panic;
 use std::iter::once;
 
-use proc_macro2::Span;
-use quote::ToTokens;
+use proc_macro2::{Span, TokenStream};
 use syn::punctuated::Punctuated;
-use syn::token::{Bracket, Colon, Gt, Lt, Semi};
+use syn::token::{Colon, Gt, Lt};
 use syn::visit::Visit;
 use syn::visit_mut::VisitMut;
 use syn::{
-    visit, AngleBracketedGenericArguments, Expr, ExprLit, Field, GenericArgument, Ident, ImplItem,
-    Item, ItemEnum, ItemImpl, LitInt, PathArguments, Type, TypeArray, Variant, Visibility,
+    parse_quote, visit, AngleBracketedGenericArguments, Attribute, Field, GenericArgument, Ident,
+    ImplItem, Item, ItemEnum, ItemImpl, Path, PathArguments, Token, Type, Variant, Visibility,
 };
-use syn::{Fields, ItemStruct};
+use syn::{Error, Fields, ItemStruct, Result};
+
+// Every type a parameter can be, which is everything with a ParamStorage impl in helpers.rs
+pub const PARAMETER_TYPES: &[&str] = &[
+    "Param",
+    "HalfParam",
+    "bool",
+    "Q",
+    "SlideTime",
+    "FalconEnvAmount",
+    "VibratoFreq",
+    "Note",
+    "Freq",
+    "EnvValue",
+];
+
+pub fn check_parameter_type(ty: &Type) -> Result<()> {
+    let name = match ty {
+        Type::Path(p) if p.qself.is_none() => p.path.segments.last().map(|s| s.ident.to_string()),
+        _ => None,
+    };
+    match name {
+        Some(name) if PARAMETER_TYPES.contains(&name.as_str()) => Ok(()),
+        _ => Err(Error::new_spanned(
+            ty,
+            format!(
+                "parameters have to be one of {}, as those are the types with Parameter conversions",
+                PARAMETER_TYPES.join(", ")
+            ),
+        )),
+    }
+}
+
+// The DeviceParameters impl for the parameter enum called name
+pub fn parameters_impl(name: &Ident, parameters: &[(Ident, Type)]) -> TokenStream {
+    let count = parameters.len();
+    let idents = parameters.iter().map(|p| &p.0).collect::<Vec<_>>();
+    let types = parameters.iter().map(|p| &p.1).collect::<Vec<_>>();
+    quote! {
+        impl crate::device::DeviceParameters for #name {
+            const COUNT: usize = #count;
+            fn index(self) -> usize {
+                self as usize
+            }
+            fn precision(self) -> crate::helpers::ParamPrecision {
+                match self {
+                    #( #name::#idents => <#types as crate::helpers::ParamStorage>::PRECISION, )*
+                }
+            }
+            fn to_chunk<T: crate::helpers::Parameter>(self, value: T) -> i32 {
+                match self {
+                    #( #name::#idents => {
+                        let temp: #types = value.into();
+                        crate::helpers::ParamStorage::to_chunk(temp)
+                    }, )*
+                }
+            }
+            fn from_chunk<T: crate::helpers::Parameter>(self, bits: i32) -> T {
+                match self {
+                    #( #name::#idents => {
+                        let temp: #types = crate::helpers::ParamStorage::from_chunk(bits);
+                        temp.into()
+                    }, )*
+                }
+            }
+            fn convert<T: crate::helpers::Parameter>(self, value: crate::helpers::Param) -> T {
+                match self {
+                    #( #name::#idents => {
+                        let temp: #types = value.into();
+                        temp.into()
+                    }, )*
+                }
+            }
+        }
+    }
+}
+
+// The Device methods that only need the chunk and the DeviceParameters impl
+pub fn device_methods(count: &TokenStream) -> TokenStream {
+    quote! {
+        fn set_param<T: Parameter>(&mut self, ty: Self::Param, value: T) {
+            self._chunk_data[ty as usize] = ty.to_chunk(value);
+        }
+        fn get_param<T: Parameter>(&self, ty: Self::Param) -> T {
+            ty.from_chunk(self._chunk_data[ty as usize])
+        }
+        fn param_precision(ty: Self::Param) -> ParamPrecision {
+            ty.precision()
+        }
+        fn set_chunk(&mut self, chunk: [i32; #count]) {
+            self._chunk_data = chunk;
+        }
+        fn get_chunk(&self) -> [i32; #count] {
+            self._chunk_data
+        }
+    }
+}
+
+// Snapshot for a device, which is a clone of the whole thing
+pub fn snapshot_impl(name: &Ident) -> TokenStream {
+    quote! {
+        impl crate::device::Snapshot for #name {
+            type State = Self;
+            fn snapshot(&self) -> Self {
+                self.clone()
+            }
+            fn restore(&mut self, state: &Self) {
+                self.clone_from(state);
+            }
+        }
+    }
+}
+
+// Adds #[derive(Clone)] unless the struct already derives it, as snapshots need it
+pub fn derive_clone(attrs: &mut Vec<Attribute>) {
+    let derives_clone = attrs.iter().filter(|a| a.path.is_ident("derive")).any(|a| {
+        a.parse_args_with(Punctuated::<Path, Token![,]>::parse_terminated)
+            .map(|paths| {
+                paths
+                    .iter()
+                    .any(|p| p.segments.last().map_or(false, |s| s.ident == "Clone"))
+            })
+            .unwrap_or(false)
+    });
+    if !derives_clone {
+        attrs.push(parse_quote!(#[derive(Clone)]));
+    }
+}
+
+// Turns a list of errors into one, so they're all reported at once
+pub fn combine(errors: Vec<Error>) -> Result<()> {
+    let mut errors = errors.into_iter();
+    match errors.next() {
+        Some(mut first) => {
+            for e in errors {
+                first.combine(e);
+            }
+            Err(first)
+        }
+        None => Ok(()),
+    }
+}
+
+// The struct called name, or an error pointing at what it's needed for
+pub fn find_struct(structs: &[Ident], name: &str, needed_by: &Ident, what: &str) -> Result<Ident> {
+    structs.iter().find(|s| *s == name).cloned().ok_or_else(|| {
+        Error::new(
+            needed_by.span(),
+            format!("{} needs a {} struct called {}", needed_by, what, name),
+        )
+    })
+}
+
+// The struct ending in Parameters, there should be exactly one
+pub fn find_parameters_struct(structs: &[Ident]) -> Result<Ident> {
+    let mut found = structs
+        .iter()
+        .filter(|s| s.to_string().ends_with("Parameters"));
+    match (found.next(), found.next()) {
+        (Some(p), None) => Ok(p.clone()),
+        (Some(_), Some(second)) => Err(Error::new(
+            second.span(),
+            "only one struct ending in Parameters is allowed in a device",
+        )),
+        (None, _) => Err(Error::new(
+            Span::call_site(),
+            "a device needs a struct ending in Parameters, e.g. FalconParameters for Falcon",
+        )),
+    }
+}
 
 pub struct NameGetVisitor {
     structs: Vec<Ident>,
@@ -22,27 +189,12 @@ impl NameGetVisitor {
         NameGetVisitor { structs: vec![] }
     }
 
-    pub fn find_device_name(&mut self) -> (Ident, Ident) {
-        if let Some(p) = self
-            .structs
-            .iter()
-            .filter(|s| s.to_string().ends_with("Parameters"))
-            .next()
-        {
-            let string = p.to_string();
-            let n = string.strip_suffix("Parameters").unwrap();
-            (
-                p.clone(),
-                self.structs
-                    .iter()
-                    .filter(|s| s.to_string() == n)
-                    .next()
-                    .expect(&format!("Main struct for {} not found", n))
-                    .clone(),
-            )
-        } else {
-            panic!("Parameters struct not found");
-        }
+    pub fn find_device_name(&mut self) -> Result<(Ident, Ident)> {
+        let p = find_parameters_struct(&self.structs)?;
+        let string = p.to_string();
+        let n = string.strip_suffix("Parameters").unwrap_or_default();
+        let device = find_struct(&self.structs, n, &p, "main")?;
+        Ok((p, device))
     }
 }
 
@@ -58,6 +210,7 @@ impl<'ast> Visit<'ast> for NameGetVisitor {
 pub struct ParameterVisitor {
     look_for: Ident,
     pub parameters: Vec<Field>,
+    pub errors: Vec<Error>,
 }
 
 impl ParameterVisitor {
@@ -65,6 +218,7 @@ impl ParameterVisitor {
         ParameterVisitor {
             look_for,
             parameters: vec![],
+            errors: vec![],
         }
     }
 }
@@ -78,7 +232,22 @@ impl VisitMut for ParameterVisitor {
                     self.parameters.extend(f.named.iter().cloned());
                     brace = f.brace_token;
                 } else {
-                    panic!("Struct {} in wrong format", self.look_for);
+                    self.errors.push(Error::new_spanned(
+                        &item.fields,
+                        format!("{} needs named fields, one per parameter", self.look_for),
+                    ));
+                    return;
+                }
+                if !item.generics.params.is_empty() {
+                    self.errors.push(Error::new_spanned(
+                        &item.generics,
+                        format!("{} can't be generic", self.look_for),
+                    ));
+                }
+                for field in self.parameters.iter() {
+                    if let Err(e) = check_parameter_type(&field.ty) {
+                        self.errors.push(e);
+                    }
                 }
                 let _enum = ItemEnum {
                     attrs: item.attrs.clone(),
@@ -104,19 +273,27 @@ impl VisitMut for ParameterVisitor {
 
 pub struct DeviceVisitor {
     look_for: Ident,
-    length: usize,
+    // How many parameters there are, either a literal or DeviceParameters::COUNT
+    count: TokenStream,
+    pub errors: Vec<Error>,
 }
 
 impl DeviceVisitor {
-    pub fn new(look_for: Ident, length: usize) -> DeviceVisitor {
-        DeviceVisitor { look_for, length }
+    pub fn new(look_for: Ident, count: TokenStream) -> DeviceVisitor {
+        DeviceVisitor {
+            look_for,
+            count,
+            errors: vec![],
+        }
     }
 }
 
 impl VisitMut for DeviceVisitor {
     fn visit_item_struct_mut(&mut self, node: &mut ItemStruct) {
         if node.ident == self.look_for {
+            derive_clone(&mut node.attrs);
             if let Fields::Named(f) = &mut node.fields {
+                let count = &self.count;
                 f.named.push(Field {
                     attrs: vec![],
                     vis: Visibility::Inherited,
@@ -124,27 +301,13 @@ impl VisitMut for DeviceVisitor {
                     colon_token: Some(Colon {
                         spans: [Span::call_site()],
                     }),
-                    ty: Type::Array(TypeArray {
-                        bracket_token: Bracket {
-                            span: Span::call_site(),
-                        },
-                        elem: Box::new(Type::Verbatim(
-                            Ident::new("i32", Span::call_site()).to_token_stream(),
-                        )),
-                        semi_token: Semi {
-                            spans: [Span::call_site()],
-                        },
-                        len: Expr::Lit(ExprLit {
-                            attrs: vec![],
-                            lit: syn::Lit::Int(LitInt::new(
-                                &self.length.to_string(),
-                                Span::call_site(),
-                            )),
-                        }),
-                    }),
+                    ty: parse_quote!([i32; #count]),
                 });
             } else {
-                panic!("Struct {} in wrong format", self.look_for);
+                self.errors.push(Error::new_spanned(
+                    &node.fields,
+                    format!("{} needs named fields for device! to add to", self.look_for),
+                ));
             }
         }
     }
@@ -153,15 +316,18 @@ impl VisitMut for DeviceVisitor {
 pub struct ImplVisitor {
     look_for: String,
     parameters_name: Ident,
-    parameters: Vec<Field>,
+    count: TokenStream,
+    // Whether the impl Device for the device was there to fill in
+    pub found: bool,
 }
 
 impl ImplVisitor {
-    pub fn new(look_for: String, parameters_name: Ident, parameters: Vec<Field>) -> ImplVisitor {
+    pub fn new(look_for: String, parameters_name: Ident, count: TokenStream) -> ImplVisitor {
         ImplVisitor {
             look_for,
             parameters_name,
-            parameters,
+            count,
+            found: false,
         }
     }
 }
@@ -188,52 +354,16 @@ impl VisitMut for ImplVisitor {
                     .collect::<Vec<_>>()
                     == ["Device"]
                 {
+                    self.found = true;
                     let name = self.look_for.clone();
                     let parameters_name = self.parameters_name.clone();
-                    let len = self.parameters.len();
-                    let idents = self
-                        .parameters
-                        .iter()
-                        .map(|m| m.ident.clone().unwrap())
-                        .collect::<Vec<_>>();
-                    let types = self
-                        .parameters
-                        .iter()
-                        .map(|m| m.ty.clone())
-                        .collect::<Vec<_>>();
+                    let count = &self.count;
 
                     node.items.push(ImplItem::Verbatim(quote! {
                     const NAME: &'static str = #name;}));
                     node.items.push(ImplItem::Verbatim(quote! {
                     type Param = #parameters_name;}));
-                    node.items.push(ImplItem::Verbatim(quote! {
-                    fn set_param<T: Parameter>(&mut self, ty: Self::Param, value: T) {
-                        let loc = ty as usize;
-                        match ty {
-                            #( #parameters_name::#idents => {
-                                let temp: #types = value.into();
-                                self._chunk_data[loc] = temp.into();
-                            }, )*
-                        }
-                    }}));
-                    node.items.push(ImplItem::Verbatim(quote! {
-                    fn get_param<T: Parameter>(&self, ty: Self::Param) -> T {
-                        let loc = ty as usize;
-                        match ty {
-                            #( #parameters_name::#idents => {
-                                let temp: #types = self._chunk_data[loc].into();
-                                temp.into()
-                            }, )*
-                        }
-                    }}));
-                    node.items.push(ImplItem::Verbatim(quote! {
-                    fn set_chunk(&mut self, chunk: [i32; #len]) {
-                        self._chunk_data = chunk;
-                    }}));
-                    node.items.push(ImplItem::Verbatim(quote! {
-                    fn get_chunk(&self) -> [i32; #len]{
-                        self._chunk_data
-                    }}));
+                    node.items.push(ImplItem::Verbatim(device_methods(count)));
 
                     node.trait_
                         .as_mut()
@@ -250,13 +380,7 @@ impl VisitMut for ImplVisitor {
                                 spans: [Span::call_site()],
                             },
                             args: Punctuated::from_iter(once(GenericArgument::Const(
-                                syn::Expr::Lit(ExprLit {
-                                    attrs: vec![],
-                                    lit: syn::Lit::Int(LitInt::new(
-                                        &len.to_string(),
-                                        Span::call_site(),
-                                    )),
-                                }),
+                                parse_quote!({ #count }),
                             ))),
                             gt_token: Gt {
                                 spans: [Span::call_site()],
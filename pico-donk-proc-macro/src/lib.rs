@@ -1,6 +1,7 @@
 #![feature(concat_idents)]
 #![feature(generic_const_exprs)]
 mod device;
+mod scala;
 mod synth_device;
 
 extern crate proc_macro;
@@ -11,11 +12,19 @@ extern crate quote;
 use device::*;
 use fixed::FixedI32;
 use proc_macro::TokenStream;
+use proc_macro2::Span;
 use quote::ToTokens;
+use scala::*;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
 use syn::visit::Visit;
 use syn::visit_mut::VisitMut;
 use syn::File;
 use syn::Item;
+use syn::{
+    parse_quote, Data, DeriveInput, Expr, ExprLit, Field, Fields, ItemImpl, ItemStruct, Type,
+};
+use syn::{Error, Ident, Lit, MetaNameValue, Result, Token};
 use synth_device::*;
 
 type Sample = FixedI32<24>;
@@ -25,125 +34,403 @@ type Quarter = FixedI32<8>;
 #[proc_macro]
 pub fn types(_: TokenStream) -> TokenStream {
     TokenStream::from(quote! {
+    #[cfg(not(feature = "float"))]
     use fixed::FixedI32;
+    #[cfg(not(feature = "float"))]
     pub type Sample = FixedI32<24>;
+    #[cfg(not(feature = "float"))]
     pub type Half = FixedI32<16>;
+    #[cfg(not(feature = "float"))]
     pub type Quarter = FixedI32<8>;
+    #[cfg(feature = "float")]
+    pub type Sample = crate::float::Float<24>;
+    #[cfg(feature = "float")]
+    pub type Half = crate::float::Float<16>;
+    #[cfg(feature = "float")]
+    pub type Quarter = crate::float::Float<8>;
     })
 }
 
-#[proc_macro]
-pub fn tables(_: TokenStream) -> TokenStream {
-    let fast_sin_tab_log2_size: usize = 9; // size = 512
-    let fast_sin_tab_size: usize = 1 << fast_sin_tab_log2_size;
-    let adjusted_fast_sin_tab_size: usize = fast_sin_tab_size + 1;
-    let fast_sin_tab = (0..adjusted_fast_sin_tab_size)
-        .map(|f| {
-            Sample::from_num(
-                ((((f as f64) * (core::f64::consts::TAU / fast_sin_tab_size as f64)).sin() + 1.)
-                    / 2.)
-                    + 1.,
-            )
-            .to_bits()
-        })
+// Builds one of the interpolated lookup tables used by TableLookup::lookup, with
+// (1 << log2_size) + 1 entries of f(x) for x going from 0 to 1.
+fn lookup_table(name: &str, log2_size: usize, f: impl Fn(f64) -> f64) -> proc_macro2::TokenStream {
+    let size: usize = 1 << log2_size;
+    let adjusted_size: usize = size + 1;
+    let table = (0..adjusted_size)
+        .map(|i| Sample::from_num(f(i as f64 / size as f64)).to_bits())
         .collect::<Vec<i32>>();
+    let log2_size_name = format_ident!("FAST_{}_TAB_LOG2_SIZE", name);
+    let size_name = format_ident!("FAST_{}_TAB_SIZE", name);
+    let adjusted_size_name = format_ident!("ADJUSTED_FAST_{}_TAB_SIZE", name);
+    let table_name = format_ident!("FAST_{}_TAB", name);
+    quote! {
+        pub const #log2_size_name: usize = #log2_size;
+        pub const #size_name: usize = #size;
+        pub const #adjusted_size_name: usize = #adjusted_size;
+        pub const #table_name: [Sample; #adjusted_size] = [
+            #(Sample::from_bits(#table)),*
+        ];
+    }
+}
+
+// tables!(sin = 9, exp = 9, log2 = 9, tanh = 9, pow = 9, sinc = 9, sinc_taps = 8)
+// Every option is optional. Table options are the log2 of the table size, so each one
+// trades flash for accuracy. sinc isn't needed by anything in the core, so can be turned
+// off with sinc = false.
+#[proc_macro]
+pub fn tables(input: TokenStream) -> TokenStream {
+    expand_tables(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_tables(input: TokenStream) -> Result<proc_macro2::TokenStream> {
+    let options = syn::parse::Parser::parse(
+        Punctuated::<MetaNameValue, Token![,]>::parse_terminated,
+        input,
+    )?;
+
+    let mut sin: usize = 9;
+    let mut exp: usize = 9;
+    let mut log2: usize = 9;
+    let mut tanh: usize = 9;
+    let mut pow: usize = 9;
+    let mut sinc: Option<usize> = Some(9);
+    let mut sinc_taps: usize = 8;
+    for option in options.iter() {
+        let name = option
+            .path
+            .get_ident()
+            .map(|i| i.to_string())
+            .unwrap_or_default();
+        let value = match &option.lit {
+            Lit::Int(i) => Some(i.base10_parse::<usize>()?),
+            Lit::Bool(b) if !b.value => None,
+            lit => {
+                return Err(Error::new_spanned(
+                    lit,
+                    format!("table option {} should be a size or false", name),
+                ))
+            }
+        };
+        let size = || {
+            let size = value.ok_or_else(|| {
+                Error::new_spanned(
+                    &option.lit,
+                    format!("the {} table is needed by helpers", name),
+                )
+            })?;
+            // TableLookup::lookup runs out of bits to interpolate with past this
+            if size > 12 && name != "sinc_taps" {
+                return Err(Error::new_spanned(
+                    &option.lit,
+                    format!("the {} table can't be bigger than 2^12", name),
+                ));
+            }
+            Ok(size)
+        };
+        match name.as_str() {
+            "sin" => sin = size()?,
+            "exp" => exp = size()?,
+            "log2" => log2 = size()?,
+            "tanh" => tanh = size()?,
+            "pow" => pow = size()?,
+            "sinc" => sinc = value.map(|_| size()).transpose()?,
+            "sinc_taps" => sinc_taps = size()?,
+            _ => {
+                return Err(Error::new_spanned(
+                    &option.path,
+                    format!(
+                    "unknown table {}, expected one of sin, exp, log2, tanh, pow, sinc, sinc_taps",
+                    name
+                ),
+                ))
+            }
+        }
+    }
+
+    // Offset so the table is always positive, sin returns between 1 and 2
+    let fast_sin_tab = lookup_table("SIN", sin, |x| {
+        (((x * core::f64::consts::TAU).sin() + 1.) / 2.) + 1.
+    });
 
     let note_tab_size: usize = 128;
     let note_tab = (0..note_tab_size)
         .map(|f| Half::from_num(((f as f64 - 69.0) / 12.0).exp2() * 440.0).to_bits())
         .collect::<Vec<i32>>();
 
-    let fast_exp_tab_log2_size: usize = 9; // size = 512
-    let fast_exp_tab_size: usize = 1 << fast_exp_tab_log2_size;
-    let adjusted_fast_exp_tab_size: usize = fast_exp_tab_size + 1;
-    let fast_exp_tab = (0..adjusted_fast_exp_tab_size)
-        .map(|f| Sample::from_num((f as f64 / fast_exp_tab_size as f64).exp2() - 1.).to_bits())
-        .collect::<Vec<i32>>();
+    let fast_exp_tab = lookup_table("EXP", exp, |x| x.exp2() - 1.);
+
+    let fast_log2_tab = lookup_table("LOG2", log2, |x| (x + 1.).log2());
+
+    // tanh(x) for x between 0 and FAST_TANH_TAB_RANGE
+    let fast_tanh_tab_range: i32 = 4;
+    let fast_tanh_tab = lookup_table("TANH", tanh, |x| (x * fast_tanh_tab_range as f64).tanh());
+
+    // The exponential envelope curve, (1 - 2^(-8x)) / (1 - 2^-8)
+    let fast_pow_tab = lookup_table("POW", pow, |x| {
+        (1. - (-8. * x).exp2()) / (1. - (-8.0f64).exp2())
+    });
+
+    // One side of a blackman windowed sinc, x from 0 to 1 covers FAST_SINC_TAB_TAPS / 2 zero crossings
+    let fast_sinc_tab = sinc.map(|sinc| {
+        let half_taps = sinc_taps as f64 / 2.;
+        let table = lookup_table("SINC", sinc, |x| {
+            let t = x * half_taps;
+            let sinc = if t == 0. {
+                1.
+            } else {
+                (t * core::f64::consts::PI).sin() / (t * core::f64::consts::PI)
+            };
+            let w = (x + 1.) * core::f64::consts::PI;
+            let window = 0.42 - 0.5 * w.cos() + 0.08 * (2. * w).cos();
+            sinc * window
+        });
+        quote! {
+            pub const FAST_SINC_TAB_TAPS: usize = #sinc_taps;
+            #table
+        }
+    });
 
     let expanded = quote! {
-        pub const FAST_SIN_TAB_LOG2_SIZE: usize = #fast_sin_tab_log2_size; // size = 512
-        pub const FAST_SIN_TAB_SIZE: usize = #fast_sin_tab_size;
-        pub const ADJUSTED_FAST_SIN_TAB_SIZE: usize = #adjusted_fast_sin_tab_size;
-        pub const FAST_SIN_TAB: [Sample; #adjusted_fast_sin_tab_size] = [
-            #(Sample::from_bits(#fast_sin_tab)),*
-        ];
+        #fast_sin_tab
         pub const NOTE_TAB_SIZE: usize = #note_tab_size;
-        pub const NOTE_TAB: [Sample; #note_tab_size] = [
-            #(Sample::from_bits(#note_tab)),*
-        ];
-        pub const FAST_EXP_TAB_LOG2_SIZE: usize = #fast_exp_tab_log2_size; // size = 512
-        pub const FAST_EXP_TAB_SIZE: usize = #fast_exp_tab_size;
-        pub const ADJUSTED_FAST_EXP_TAB_SIZE: usize = #adjusted_fast_exp_tab_size;
-        pub const FAST_EXP_TAB: [Sample; #adjusted_fast_exp_tab_size] = [
-            #(Sample::from_bits(#fast_exp_tab)),*
+        pub const NOTE_TAB: [Half; #note_tab_size] = [
+            #(Half::from_bits(#note_tab)),*
         ];
+        #fast_exp_tab
+        #fast_log2_tab
+        pub const FAST_TANH_TAB_RANGE: i32 = #fast_tanh_tab_range;
+        #fast_tanh_tab
+        #fast_pow_tab
+        #fast_sinc_tab
+    };
+
+    Ok(expanded)
+}
+
+struct TuningArgs {
+    name: Ident,
+    options: Punctuated<MetaNameValue, Token![,]>,
+}
+
+impl Parse for TuningArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        let options = if input.is_empty() {
+            Punctuated::new()
+        } else {
+            input.parse::<Token![,]>()?;
+            Punctuated::parse_terminated(input)?
+        };
+        Ok(TuningArgs { name, options })
+    }
+}
+
+// tuning!(NAME, scl = "file.scl", kbm = "file.kbm", reference = 440.0)
+// Every option is optional, the default is 12-TET with A4 at 440hz.
+// Files are relative to the crate root, and are parsed at build time into a const table.
+#[proc_macro]
+pub fn tuning(input: TokenStream) -> TokenStream {
+    expand_tuning(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_tuning(input: TokenStream) -> Result<proc_macro2::TokenStream> {
+    let args: TuningArgs = syn::parse(input)?;
+    let mut scale = Scale::twelve_tet();
+    let mut map = None;
+    let mut reference = None;
+    let root = std::env::var("CARGO_MANIFEST_DIR").unwrap_or_default();
+    let read = |lit: &Lit| -> Result<String> {
+        if let Lit::Str(path) = lit {
+            let path = std::path::Path::new(&root).join(path.value());
+            std::fs::read_to_string(&path).map_err(|e| {
+                Error::new_spanned(
+                    lit,
+                    format!("could not read tuning file {}: {}", path.display(), e),
+                )
+            })
+        } else {
+            Err(Error::new_spanned(
+                lit,
+                "tuning file paths should be strings",
+            ))
+        }
     };
+    for option in args.options.iter() {
+        let at_file = |e: String| Error::new_spanned(&option.lit, e);
+        match option.path.get_ident().map(|i| i.to_string()).as_deref() {
+            Some("scl") => scale = Scale::parse(&read(&option.lit)?).map_err(at_file)?,
+            Some("kbm") => map = Some(KeyboardMap::parse(&read(&option.lit)?).map_err(at_file)?),
+            Some("reference") => {
+                reference = Some(match &option.lit {
+                    Lit::Float(f) => f.base10_parse::<f64>()?,
+                    Lit::Int(i) => i.base10_parse::<f64>()?,
+                    lit => {
+                        return Err(Error::new_spanned(
+                            lit,
+                            "tuning reference should be a frequency",
+                        ))
+                    }
+                })
+            }
+            _ => {
+                return Err(Error::new_spanned(
+                    &option.path,
+                    "unknown tuning option, expected one of scl, kbm, reference",
+                ))
+            }
+        }
+    }
+    let mut map = map.unwrap_or_else(|| KeyboardMap::linear(440.0, scale.cents.len() as i32));
+    if let Some(reference) = reference {
+        map.reference_freq = reference;
+    }
+
+    let note_tab_size: usize = 128;
+    let table = note_table(&scale, &map, note_tab_size)
+        .map_err(|e| Error::new(args.name.span(), e))?
+        .iter()
+        .map(|f| Half::saturating_from_num(*f).to_bits())
+        .collect::<Vec<i32>>();
+    let name = args.name;
 
-    // Hand the output tokens back to the compiler.
-    TokenStream::from(expanded)
+    Ok(quote! {
+        pub const #name: crate::tuning::Tuning = crate::tuning::Tuning {
+            table: [#(Half::from_bits(#table)),*],
+        };
+    })
 }
 
 #[proc_macro]
 pub fn device(input: TokenStream) -> TokenStream {
-    let mut ast: File = syn::parse(input.clone()).unwrap();
-    //panic!("{:?}", ast);
+    expand_device(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_device(input: TokenStream) -> Result<proc_macro2::TokenStream> {
+    let mut ast: File = syn::parse(input)?;
     let (parameters_name, device_name) = {
         let mut ngv = NameGetVisitor::new();
         ngv.visit_file(&ast);
-        ngv.find_device_name()
+        ngv.find_device_name()?
     };
-    eprintln!("Creating device {}", device_name);
     let parameters = {
         let mut pv = ParameterVisitor::new(parameters_name.clone());
         pv.visit_file_mut(&mut ast);
+        combine(pv.errors)?;
         pv.parameters
     };
+    let len = parameters.len();
+    let count = quote!(#len);
     {
-        let mut dv = DeviceVisitor::new(device_name.clone(), parameters.len());
+        let mut dv = DeviceVisitor::new(device_name.clone(), count.clone());
         dv.visit_file_mut(&mut ast);
+        combine(dv.errors)?;
     };
     let name = device_name.to_string();
+    ast.items.push(Item::Verbatim(parameters_impl(
+        &parameters_name,
+        &parameter_pairs(&parameters),
+    )));
+    ast.items.push(Item::Verbatim(snapshot_impl(&device_name)));
     {
-        let mut iv = ImplVisitor::new(name, parameters_name, parameters);
+        let mut iv = ImplVisitor::new(name.clone(), parameters_name, count);
         iv.visit_file_mut(&mut ast);
+        if !iv.found {
+            return Err(Error::new(
+                device_name.span(),
+                format!(
+                    "device! needs an `impl Device for {} {{}}` to fill in",
+                    name
+                ),
+            ));
+        }
     };
 
     ast.items
-        .push(Item::Verbatim(quote! {use crate::helpers::Parameter;}));
-    ast.items
-        .push(Item::Verbatim(quote! {use crate::device::Device;}));
+        .push(Item::Verbatim(quote! {use crate::helpers::*;}));
+    ast.items.push(Item::Verbatim(
+        quote! {use crate::device::{Device, DeviceParameters};},
+    ));
 
-    ast.to_token_stream().into()
+    Ok(ast.to_token_stream())
 }
 
+// A #[synth(voices = 16, events = 8, active_notes = 4)] on the main struct sets how big its
+// arrays are, every size is optional and defaults to 8 voices, 8 events and 4 notes.
 #[proc_macro]
 pub fn synth_device(input: TokenStream) -> TokenStream {
-    let mut ast: File = syn::parse(input.clone()).unwrap();
-    //panic!("{:?}", ast);
+    expand_synth_device(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_synth_device(input: TokenStream) -> Result<proc_macro2::TokenStream> {
+    let mut ast: File = syn::parse(input)?;
     let (parameters_name, device_name, voice_name) = {
         let mut ngv = SynthNameGetVisitor::new();
         ngv.visit_file(&ast);
-        ngv.find_device_name()
+        ngv.find_device_name()?
     };
-    eprintln!("Creating synth device {}", device_name);
     let parameters = {
         let mut pv = ParameterVisitor::new(parameters_name.clone());
         pv.visit_file_mut(&mut ast);
+        combine(pv.errors)?;
         pv.parameters
     };
+    let len = parameters.len();
+    let count = quote!(#len);
+    let sizes = take_synth_sizes(&mut ast, &device_name)?;
     {
-        let mut dv =
-            SynthDeviceVisitor::new(device_name.clone(), voice_name.clone(), parameters.len());
+        let mut dv = SynthDeviceVisitor::new(
+            device_name.clone(),
+            voice_name.clone(),
+            parameters_name.clone(),
+            count.clone(),
+            sizes,
+        );
         dv.visit_file_mut(&mut ast);
+        combine(dv.errors)?;
     };
     {
-        let mut vv = SynthVoiceVisitor::new(voice_name.clone(), parameters_name.clone());
+        let mut vv = SynthVoiceVisitor::new(voice_name.clone());
         vv.visit_file_mut(&mut ast);
+        combine(vv.errors)?;
     };
     let name = device_name.to_string();
     {
-        let mut iv = SynthImplVisitor::new(name.clone(), voice_name, parameters.len());
+        let mut iv = SynthImplVisitor::new(
+            name.clone(),
+            voice_name.clone(),
+            parameters_name.clone(),
+            count.clone(),
+            sizes,
+        );
         iv.visit_file_mut(&mut ast);
+        let mut errors = vec![];
+        if !iv.found_device {
+            errors.push(Error::new(
+                device_name.span(),
+                format!(
+                    "synth_device! needs an `impl SynthDevice for {} {{}}` to fill in",
+                    name
+                ),
+            ));
+        }
+        if !iv.found_voice {
+            errors.push(Error::new(
+                voice_name.span(),
+                format!(
+                    "synth_device! needs an `impl Voice for {} {{ ... }}` to fill in",
+                    voice_name
+                ),
+            ));
+        }
+        combine(errors)?;
     };
 
     ast.items
@@ -151,47 +438,354 @@ pub fn synth_device(input: TokenStream) -> TokenStream {
     ast.items.push(Item::Verbatim(quote! {use crate::cst::*;}));
     ast.items
         .push(Item::Verbatim(quote! {use crate::device::*;}));
+    ast.items
+        .push(Item::Verbatim(quote! {use crate::modulation::*;}));
+    ast.items
+        .push(Item::Verbatim(quote! {use crate::tuning::*;}));
 
-    let len = parameters.len();
-    let idents = parameters
-        .iter()
-        .map(|m| m.ident.clone().unwrap())
-        .collect::<Vec<_>>();
-    let types = parameters.iter().map(|m| m.ty.clone()).collect::<Vec<_>>();
-
+    ast.items.push(Item::Verbatim(parameters_impl(
+        &parameters_name,
+        &parameter_pairs(&parameters),
+    )));
+    ast.items.push(Item::Verbatim(snapshot_impl(&device_name)));
+    let methods = device_methods(&count);
     let run = get_run();
 
     ast.items.push(Item::Verbatim(quote! {
         impl Device<#len> for #device_name {
             const NAME: &'static str = #name;
             type Param = #parameters_name;
-            fn set_param<T: Parameter>(&mut self, ty: Self::Param, value: T) {
-                let loc = ty as usize;
-                match ty {
-                    #( #parameters_name::#idents => {
-                        let temp: #types = value.into();
-                        self._chunk_data[loc] = temp.into();
-                    }, )*
-                }
+            #methods
+            #run
+        }
+    }));
+
+    Ok(ast.to_token_stream())
+}
+
+// The fields of a parameters struct as (variant, type) pairs for parameters_impl
+fn parameter_pairs(parameters: &[Field]) -> Vec<(Ident, Type)> {
+    parameters
+        .iter()
+        .map(|p| (p.ident.clone().unwrap(), p.ty.clone()))
+        .collect()
+}
+
+// The name = value arguments of the attribute forms, e.g. voice = FalconVoice or voices = 16
+struct AttributeArg {
+    name: Ident,
+    value: Expr,
+}
+
+impl Parse for AttributeArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name = input.parse()?;
+        input.parse::<Token![=]>()?;
+        let value = input.parse()?;
+        Ok(AttributeArg { name, value })
+    }
+}
+
+struct AttributeArgs {
+    args: Punctuated<AttributeArg, Token![,]>,
+}
+
+impl Parse for AttributeArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        Ok(AttributeArgs {
+            args: Punctuated::parse_terminated(input)?,
+        })
+    }
+}
+
+impl AttributeArgs {
+    // Checks every argument is one of expected
+    fn check(&self, attribute: &str, expected: &[&str]) -> Result<()> {
+        combine(
+            self.args
+                .iter()
+                .filter(|arg| !expected.contains(&arg.name.to_string().as_str()))
+                .map(|arg| {
+                    Error::new_spanned(
+                        &arg.name,
+                        if expected.is_empty() {
+                            format!("#[{}] doesn't take any arguments here", attribute)
+                        } else {
+                            format!(
+                                "unknown argument {}, #[{}] takes {}",
+                                arg.name,
+                                attribute,
+                                expected.join(", ")
+                            )
+                        },
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    fn find(&self, name: &str) -> Option<&Expr> {
+        self.args
+            .iter()
+            .find(|arg| arg.name == name)
+            .map(|arg| &arg.value)
+    }
+
+    // An argument that has to be there and name a type
+    fn ident(&self, attribute: &str, name: &str) -> Result<Ident> {
+        match self.find(name) {
+            Some(Expr::Path(p)) if p.path.get_ident().is_some() => {
+                Ok(p.path.get_ident().unwrap().clone())
             }
-            fn get_param<T: Parameter>(&self, ty: Self::Param) -> T {
-                let loc = ty as usize;
-                match ty {
-                    #( #parameters_name::#idents => {
-                        let temp: #types = self._chunk_data[loc].into();
-                        temp.into()
-                    }, )*
+            Some(value) => Err(Error::new_spanned(
+                value,
+                format!("{} should be the name of a type", name),
+            )),
+            None => Err(Error::new(
+                Span::call_site(),
+                format!("#[{}] needs {} = ...", attribute, name),
+            )),
+        }
+    }
+
+    // An optional array size, which has to be at least 1
+    fn size(&self, name: &str, default: usize) -> Result<usize> {
+        match self.find(name) {
+            Some(Expr::Lit(ExprLit {
+                lit: Lit::Int(i), ..
+            })) => {
+                let size = i.base10_parse::<usize>()?;
+                if size == 0 {
+                    return Err(Error::new_spanned(i, format!("{} can't be 0", name)));
                 }
+                Ok(size)
             }
-            fn set_chunk(&mut self, chunk: [i32; #len]) {
-                self._chunk_data = chunk;
-            }
-            fn get_chunk(&self) -> [i32; #len]{
-                self._chunk_data
+            Some(value) => Err(Error::new_spanned(
+                value,
+                format!("{} should be a number", name),
+            )),
+            None => Ok(default),
+        }
+    }
+
+    fn sizes(&self) -> Result<SynthSizes> {
+        let default = SynthSizes::default();
+        Ok(SynthSizes {
+            voices: self.size("voices", default.voices)?,
+            events: self.size("events", default.events)?,
+            active_notes: self.size("active_notes", default.active_notes)?,
+        })
+    }
+}
+
+// The sizes synth_device! takes from a #[synth(...)] on the main struct, which it removes
+fn take_synth_sizes(ast: &mut File, device_name: &Ident) -> Result<SynthSizes> {
+    let mut sizes = SynthSizes::default();
+    for item in ast.items.iter_mut() {
+        if let Item::Struct(s) = item {
+            if s.ident == *device_name {
+                let (synth, attrs) = s
+                    .attrs
+                    .drain(..)
+                    .partition::<Vec<_>, _>(|a| a.path.is_ident("synth"));
+                s.attrs = attrs;
+                for attr in synth {
+                    let args: AttributeArgs = attr.parse_args()?;
+                    args.check("synth", &["voices", "events", "active_notes"])?;
+                    sizes = args.sizes()?;
+                }
             }
+        }
+    }
+    Ok(sizes)
+}
+
+// #[synth(voice = FalconVoice, params = FalconParameters)] on the main struct of a synth,
+// optionally with voices = 8, events = 8, active_notes = 4 to size its arrays.
+// The attribute form of synth_device!, so the device can be written as normal Rust:
+// the params enum derives DeviceParameters, the voice struct and its impl Voice get
+// #[synth_voice], and this adds the fields and the Device and SynthDevice impls.
+// Like synth_device!, the generated code expects the module to glob import
+// cst, device, helpers, modulation and tuning.
+#[proc_macro_attribute]
+pub fn synth(args: TokenStream, input: TokenStream) -> TokenStream {
+    expand_synth(args, input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_synth(args: TokenStream, input: TokenStream) -> Result<proc_macro2::TokenStream> {
+    let args: AttributeArgs = syn::parse(args)?;
+    args.check(
+        "synth",
+        &["voice", "params", "voices", "events", "active_notes"],
+    )?;
+    let voice_name = args.ident("synth", "voice")?;
+    let parameters_name = args.ident("synth", "params")?;
+    let sizes = args.sizes()?;
+    let item: ItemStruct = syn::parse(input)?;
+    let device_name = item.ident.clone();
+    let count = quote!(<#parameters_name as DeviceParameters>::COUNT);
+    let mut ast = File {
+        shebang: None,
+        attrs: vec![],
+        items: vec![Item::Struct(item)],
+    };
+    {
+        let mut dv = SynthDeviceVisitor::new(
+            device_name.clone(),
+            voice_name.clone(),
+            parameters_name.clone(),
+            count.clone(),
+            sizes,
+        );
+        dv.visit_file_mut(&mut ast);
+        combine(dv.errors)?;
+    };
+    let name = device_name.to_string();
+    let mut synth_impl: ItemImpl = parse_quote!(impl SynthDevice for #device_name {});
+    {
+        let mut iv = SynthImplVisitor::new(
+            name.clone(),
+            voice_name,
+            parameters_name.clone(),
+            count.clone(),
+            sizes,
+        );
+        iv.visit_item_impl_mut(&mut synth_impl);
+    };
+
+    let methods = device_methods(&count);
+    let run = get_run();
+    let snapshot = snapshot_impl(&device_name);
+
+    Ok(quote! {
+        #ast
+        #synth_impl
+        #snapshot
+        impl Device<{ #count }> for #device_name {
+            const NAME: &'static str = #name;
+            type Param = #parameters_name;
+            #methods
             #run
         }
-    }));
+    })
+}
 
-    ast.to_token_stream().into()
+// #[synth_voice] on the voice struct adds the voice fields, and
+// #[synth_voice(params = FalconParameters)] on its impl Voice adds the methods synth_device! would.
+#[proc_macro_attribute]
+pub fn synth_voice(args: TokenStream, input: TokenStream) -> TokenStream {
+    expand_synth_voice(args, input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_synth_voice(args: TokenStream, input: TokenStream) -> Result<proc_macro2::TokenStream> {
+    let args: AttributeArgs = syn::parse(args)?;
+    let mut item: Item = syn::parse(input)?;
+    match &mut item {
+        Item::Struct(s) => {
+            args.check("synth_voice", &[])?;
+            let mut vv = SynthVoiceVisitor::new(s.ident.clone());
+            vv.visit_item_struct_mut(s);
+            combine(vv.errors)?;
+        }
+        Item::Impl(i) => {
+            args.check("synth_voice", &["params"])?;
+            let parameters_name = args.ident("synth_voice", "params")?;
+            let voice_name = match &*i.self_ty {
+                Type::Path(p) if p.path.get_ident().is_some() => {
+                    p.path.get_ident().unwrap().clone()
+                }
+                ty => {
+                    return Err(Error::new_spanned(
+                        ty,
+                        "#[synth_voice] has to be on `impl Voice for` the voice struct",
+                    ))
+                }
+            };
+            // Only the voice half of the visitor is wanted, so the device arguments never get used
+            let mut iv = SynthImplVisitor::new(
+                String::new(),
+                voice_name,
+                parameters_name,
+                quote!(0),
+                SynthSizes::default(),
+            );
+            iv.visit_item_impl_mut(i);
+            if !iv.found_voice {
+                return Err(Error::new_spanned(
+                    &i.self_ty,
+                    "#[synth_voice] has to be on `impl Voice for` the voice struct",
+                ));
+            }
+        }
+        item => {
+            return Err(Error::new_spanned(
+                item,
+                "#[synth_voice] goes on the voice struct or its impl Voice",
+            ))
+        }
+    }
+    Ok(item.to_token_stream())
+}
+
+// #[derive(DeviceParameters)] on a fieldless enum, one variant per parameter.
+// Variants are Params unless they say otherwise with e.g. #[param(EnvValue)].
+#[proc_macro_derive(DeviceParameters, attributes(param))]
+pub fn derive_device_parameters(input: TokenStream) -> TokenStream {
+    expand_device_parameters(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+fn expand_device_parameters(input: TokenStream) -> Result<proc_macro2::TokenStream> {
+    let input: DeriveInput = syn::parse(input)?;
+    let name = input.ident;
+    let data = match input.data {
+        Data::Enum(data) => data,
+        _ => {
+            return Err(Error::new(
+                name.span(),
+                "DeviceParameters can only be derived for an enum, one variant per parameter",
+            ))
+        }
+    };
+    let mut errors = vec![];
+    if !input.generics.params.is_empty() {
+        errors.push(Error::new_spanned(
+            &input.generics,
+            format!("{} can't be generic", name),
+        ));
+    }
+    let mut parameters = vec![];
+    for variant in data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            errors.push(Error::new_spanned(
+                &variant.fields,
+                "parameters can't have fields, the type goes in #[param(...)]",
+            ));
+        }
+        // The chunk is indexed by `ty as usize`, so the variants have to count up from 0
+        if let Some((_, discriminant)) = &variant.discriminant {
+            errors.push(Error::new_spanned(
+                discriminant,
+                "parameters can't have discriminants, they're their place in the chunk",
+            ));
+        }
+        let mut ty: Type = parse_quote!(Param);
+        for attr in variant.attrs.iter().filter(|a| a.path.is_ident("param")) {
+            match attr.parse_args::<Type>() {
+                Ok(t) => ty = t,
+                Err(e) => errors.push(e),
+            }
+        }
+        if let Err(e) = check_parameter_type(&ty) {
+            errors.push(e);
+        }
+        parameters.push((variant.ident, ty));
+    }
+    combine(errors)?;
+    Ok(parameters_impl(&name, &parameters))
 }
@@ -0,0 +1,205 @@
+// Parsing for Scala tuning files (.scl) and keyboard mappings (.kbm)
+// See https://www.huygens-fokker.org/scala/scl_format.html
+// Errors are strings, tuning! turns them into compile errors on the file's path.
+
+pub struct Scale {
+    // Cents for each degree of the scale, the last one is the period (usually 1200)
+    pub cents: Vec<f64>,
+}
+
+pub struct KeyboardMap {
+    pub size: usize,
+    // Only notes from first_note to last_note are played, the rest are silent like unmapped keys
+    pub first_note: i32,
+    pub last_note: i32,
+    pub middle_note: i32,
+    pub reference_note: i32,
+    pub reference_freq: f64,
+    pub octave_degree: i32,
+    // None for unmapped keys
+    pub map: Vec<Option<i32>>,
+}
+
+// Lines that aren't comments, with whitespace trimmed, so indented comments are still comments
+fn lines(source: &str) -> impl Iterator<Item = &str> {
+    source
+        .lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.starts_with('!'))
+}
+
+fn parse_pitch(pitch: &str) -> Result<f64, String> {
+    let pitch = pitch
+        .split_whitespace()
+        .next()
+        .ok_or("Empty pitch in scala file")?;
+    let number = |n: &str| {
+        n.parse::<f64>()
+            .map_err(|_| format!("Bad pitch {} in scala file", pitch))
+    };
+    if pitch.contains('.') {
+        number(pitch)
+    } else {
+        let ratio = if let Some((n, d)) = pitch.split_once('/') {
+            number(n)? / number(d)?
+        } else {
+            number(pitch)?
+        };
+        if ratio <= 0.0 {
+            return Err(format!("Bad ratio {} in scala file", pitch));
+        }
+        Ok(ratio.log2() * 1200.0)
+    }
+}
+
+impl Scale {
+    pub fn twelve_tet() -> Scale {
+        Scale {
+            cents: (1..=12).map(|f| f as f64 * 100.0).collect(),
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<Scale, String> {
+        let mut lines = lines(source);
+        // The description can be blank, so don't skip empty lines until after it
+        lines.next().ok_or("Scala file has no description")?;
+        let mut lines = lines.filter(|l| !l.is_empty());
+        let count = lines
+            .next()
+            .and_then(|l| l.split_whitespace().next())
+            .and_then(|l| l.parse::<usize>().ok())
+            .ok_or("Scala file has no note count")?;
+        let cents = lines
+            .take(count)
+            .map(parse_pitch)
+            .collect::<Result<Vec<_>, _>>()?;
+        if cents.len() != count || count == 0 {
+            return Err(format!(
+                "Scala file has {} notes, expected {}",
+                cents.len(),
+                count
+            ));
+        }
+        Ok(Scale { cents })
+    }
+
+    // Cents above the scale's root for any degree, including negative ones and ones past the period
+    pub fn degree_cents(&self, degree: i32) -> f64 {
+        let size = self.cents.len() as i32;
+        let period = self.cents[self.cents.len() - 1];
+        let octave = degree.div_euclid(size);
+        let step = degree.rem_euclid(size);
+        let step_cents = if step == 0 {
+            0.0
+        } else {
+            self.cents[step as usize - 1]
+        };
+        octave as f64 * period + step_cents
+    }
+}
+
+impl KeyboardMap {
+    // A linear mapping with A4 at reference_freq
+    pub fn linear(reference_freq: f64, octave_degree: i32) -> KeyboardMap {
+        KeyboardMap {
+            size: 0,
+            first_note: 0,
+            last_note: 127,
+            middle_note: 69,
+            reference_note: 69,
+            reference_freq,
+            octave_degree,
+            map: vec![],
+        }
+    }
+
+    pub fn parse(source: &str) -> Result<KeyboardMap, String> {
+        let mut lines = lines(source).filter(|l| !l.is_empty());
+        let mut next = |what: &str| -> Result<String, String> {
+            lines
+                .next()
+                .and_then(|l| l.split_whitespace().next())
+                .map(|l| l.to_owned())
+                .ok_or(format!("Keyboard map is missing the {}", what))
+        };
+        let size = next("map size")?
+            .parse::<usize>()
+            .map_err(|_| "Bad map size")?;
+        let first_note = next("first note")?
+            .parse::<i32>()
+            .map_err(|_| "Bad first note")?;
+        let last_note = next("last note")?
+            .parse::<i32>()
+            .map_err(|_| "Bad last note")?;
+        if first_note > last_note {
+            return Err(format!(
+                "Keyboard map's first note {} is after its last note {}",
+                first_note, last_note
+            ));
+        }
+        let middle_note = next("middle note")?
+            .parse::<i32>()
+            .map_err(|_| "Bad middle note")?;
+        let reference_note = next("reference note")?
+            .parse::<i32>()
+            .map_err(|_| "Bad reference note")?;
+        let reference_freq = next("reference frequency")?
+            .parse::<f64>()
+            .map_err(|_| "Bad reference frequency")?;
+        let octave_degree = next("octave degree")?
+            .parse::<i32>()
+            .map_err(|_| "Bad octave degree")?;
+        let map = (0..size)
+            .map(|_| {
+                let key = next("mapping")?;
+                if key == "x" {
+                    Ok(None)
+                } else {
+                    key.parse::<i32>()
+                        .map(Some)
+                        .map_err(|_| format!("Bad key mapping {}", key))
+                }
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(KeyboardMap {
+            size,
+            first_note,
+            last_note,
+            middle_note,
+            reference_note,
+            reference_freq,
+            octave_degree,
+            map,
+        })
+    }
+
+    // The scale degree a midi note plays, or None if it's unmapped
+    pub fn degree(&self, note: i32) -> Option<i32> {
+        if note < self.first_note || note > self.last_note {
+            return None;
+        }
+        let offset = note - self.middle_note;
+        if self.size == 0 {
+            return Some(offset);
+        }
+        let octave = offset.div_euclid(self.size as i32);
+        let key = offset.rem_euclid(self.size as i32) as usize;
+        self.map[key].map(|d| d + octave * self.octave_degree)
+    }
+}
+
+// The frequency of every midi note, unmapped notes are silent
+pub fn note_table(scale: &Scale, map: &KeyboardMap, size: usize) -> Result<Vec<f64>, String> {
+    let reference_cents = scale.degree_cents(
+        map.degree(map.reference_note)
+            .ok_or("Reference note isn't mapped")?,
+    );
+    Ok((0..size as i32)
+        .map(|n| match map.degree(n) {
+            Some(d) => {
+                map.reference_freq * ((scale.degree_cents(d) - reference_cents) / 1200.0).exp2()
+            }
+            None => 0.0,
+        })
+        .collect())
+}
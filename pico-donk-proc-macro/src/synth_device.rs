@@ -1,19 +1,19 @@
-use core::panic;
 use std::iter::once;
 
 use proc_macro2::Span;
 use proc_macro2::TokenStream;
 use quote::quote;
 use syn::punctuated::Punctuated;
-use syn::token::And;
 use syn::token::{Colon, Gt, Lt, Pub};
 use syn::visit::Visit;
 use syn::visit_mut::VisitMut;
-use syn::TypeReference;
 use syn::{
-    visit, AngleBracketedGenericArguments, ExprLit, Field, Fields, GenericArgument, Ident,
-    ImplItem, ItemImpl, ItemStruct, LitInt, PathArguments, Type, VisPublic, Visibility,
+    parse_quote, visit, AngleBracketedGenericArguments, Field, Fields, GenericArgument, Ident,
+    ImplItem, ItemImpl, ItemStruct, PathArguments, Type, VisPublic, Visibility,
 };
+use syn::{Error, Result};
+
+use crate::device::{derive_clone, find_parameters_struct, find_struct};
 
 pub struct SynthNameGetVisitor {
     structs: Vec<Ident>,
@@ -24,34 +24,13 @@ impl SynthNameGetVisitor {
         SynthNameGetVisitor { structs: vec![] }
     }
 
-    pub fn find_device_name(&mut self) -> (Ident, Ident, Ident) {
-        if let Some(p) = self
-            .structs
-            .iter()
-            .filter(|s| s.to_string().ends_with("Parameters"))
-            .next()
-        {
-            let string = p.to_string();
-            let n = string.strip_suffix("Parameters").unwrap();
-            let m = n.to_owned() + "Voice";
-            (
-                p.clone(),
-                self.structs
-                    .iter()
-                    .filter(|s| s.to_string() == n)
-                    .next()
-                    .expect(&format!("Main struct for {} not found", n))
-                    .clone(),
-                self.structs
-                    .iter()
-                    .filter(|s| s.to_string() == m)
-                    .next()
-                    .expect(&format!("Voice struct for {} not found", m))
-                    .clone(),
-            )
-        } else {
-            panic!("Parameters struct not found");
-        }
+    pub fn find_device_name(&mut self) -> Result<(Ident, Ident, Ident)> {
+        let p = find_parameters_struct(&self.structs)?;
+        let string = p.to_string();
+        let n = string.strip_suffix("Parameters").unwrap_or_default();
+        let device = find_struct(&self.structs, n, &p, "main")?;
+        let voice = find_struct(&self.structs, &(n.to_owned() + "Voice"), &device, "voice")?;
+        Ok((p, device, voice))
     }
 }
 
@@ -95,18 +74,50 @@ macro_rules! field {
         });
     };
 }
+// How many voices, queued events and held notes a synth has room for, set with
+// voices = 16, events = 8, active_notes = 4 in #[synth(...)]
+#[derive(Clone, Copy)]
+pub struct SynthSizes {
+    pub voices: usize,
+    pub events: usize,
+    pub active_notes: usize,
+}
+
+impl Default for SynthSizes {
+    fn default() -> Self {
+        SynthSizes {
+            voices: 8,
+            events: 8,
+            active_notes: 4,
+        }
+    }
+}
+
 pub struct SynthDeviceVisitor {
     look_for: Ident,
     voice_name: Ident,
-    length: usize,
+    parameters_name: Ident,
+    // How many parameters there are, either a literal or DeviceParameters::COUNT
+    count: TokenStream,
+    sizes: SynthSizes,
+    pub errors: Vec<Error>,
 }
 
 impl SynthDeviceVisitor {
-    pub fn new(look_for: Ident, voice_name: Ident, length: usize) -> SynthDeviceVisitor {
+    pub fn new(
+        look_for: Ident,
+        voice_name: Ident,
+        parameters_name: Ident,
+        count: TokenStream,
+        sizes: SynthSizes,
+    ) -> SynthDeviceVisitor {
         SynthDeviceVisitor {
             look_for,
             voice_name,
-            length,
+            parameters_name,
+            count,
+            sizes,
+            errors: vec![],
         }
     }
 }
@@ -114,9 +125,10 @@ impl SynthDeviceVisitor {
 impl VisitMut for SynthDeviceVisitor {
     fn visit_item_struct_mut(&mut self, node: &mut ItemStruct) {
         if node.ident == self.look_for {
+            derive_clone(&mut node.attrs);
             if let Fields::Named(f) = &mut node.fields {
-                let len = self.length;
-                field!(f, "_chunk_data", [i32; #len]);
+                let count = &self.count;
+                field!(f, "_chunk_data", [i32; #count]);
 
                 fieldpub!(f, "voices_unisono", Unisono);
                 fieldpub!(f, "voices_detune", Detune);
@@ -128,17 +140,35 @@ impl VisitMut for SynthDeviceVisitor {
                 fieldpub!(f, "rise", Sample);
                 fieldpub!(f, "slide", SlideTime);
 
+                fieldpub!(f, "tuning", (&'static Tuning));
+
+                fieldpub!(f, "mod_wheel", Sample);
+                let params = &self.parameters_name;
+                fieldpub!(f, "mod_matrix", (ModMatrix<#params>));
+                fieldpub!(f, "mod_lfos", [Lfo; 2]);
+
                 field!(f, "mono_active", bool);
                 field!(f, "note_count", u8);
 
-                field!(f, "note_log", [Note; Self::MAX_ACTIVE_NOTES]);
-                field!(f, "active_notes", [bool; Self::MAX_ACTIVE_NOTES]);
+                let SynthSizes {
+                    voices,
+                    events,
+                    active_notes,
+                } = self.sizes;
+                field!(f, "note_log", [Note; #active_notes]);
+                field!(f, "active_notes", [bool; #active_notes]);
 
                 let name = &self.voice_name;
-                field!(f, "voices", [#name; Self::MAX_VOICES]);
-                field!(f, "events", [Event; Self::MAX_EVENTS]);
+                field!(f, "voices", (Voices<#name, #voices>));
+                field!(f, "events", (EventQueue<#events>));
             } else {
-                panic!("Struct {} in wrong format", self.look_for);
+                self.errors.push(Error::new_spanned(
+                    &node.fields,
+                    format!(
+                        "{} needs named fields for synth_device! to add to",
+                        self.look_for
+                    ),
+                ));
             }
         }
     }
@@ -146,14 +176,14 @@ impl VisitMut for SynthDeviceVisitor {
 
 pub struct SynthVoiceVisitor {
     look_for: Ident,
-    parameters: Ident,
+    pub errors: Vec<Error>,
 }
 
 impl SynthVoiceVisitor {
-    pub fn new(look_for: Ident, parameters: Ident) -> SynthVoiceVisitor {
+    pub fn new(look_for: Ident) -> SynthVoiceVisitor {
         SynthVoiceVisitor {
             look_for,
-            parameters,
+            errors: vec![],
         }
     }
 }
@@ -161,37 +191,30 @@ impl SynthVoiceVisitor {
 impl VisitMut for SynthVoiceVisitor {
     fn visit_item_struct_mut(&mut self, node: &mut ItemStruct) {
         if node.ident == self.look_for {
+            derive_clone(&mut node.attrs);
             if let Fields::Named(f) = &mut node.fields {
                 fieldpub!(f, "is_on", bool);
                 fieldpub!(f, "note", Note);
                 fieldpub!(f, "detune", Detune);
                 fieldpub!(f, "pan", Pan);
+                fieldpub!(f, "velocity", u32);
                 fieldpub!(f, "vibrato_phase", VibratoPhase);
                 fieldpub!(f, "slide_time", SlideTime);
+                fieldpub!(f, "tuning", (&'static Tuning));
+                fieldpub!(f, "rng", Rng);
                 field!(f, "slide_active", bool);
                 field!(f, "slide_delta", Half);
                 field!(f, "slide_samples", u32);
                 field!(f, "destination_note", Note);
                 field!(f, "current_note", Note);
-                let param = &self.parameters;
-                f.named.push(Field {
-                    attrs: vec![],
-                    vis: Visibility::Inherited,
-                    ident: Some(Ident::new("parameters", Span::call_site())),
-                    colon_token: Some(Colon {
-                        spans: [Span::call_site()],
-                    }),
-                    ty: Type::Reference(TypeReference {
-                        and_token: And {
-                            spans: [Span::call_site()],
-                        },
-                        lifetime: None,
-                        mutability: None,
-                        elem: Box::new(Type::Verbatim(quote! {#param})),
-                    }),
-                });
             } else {
-                panic!("Struct {} in wrong format", self.look_for);
+                self.errors.push(Error::new_spanned(
+                    &node.fields,
+                    format!(
+                        "{} needs named fields for synth_device! to add to",
+                        self.look_for
+                    ),
+                ));
             }
         }
     }
@@ -200,15 +223,30 @@ impl VisitMut for SynthVoiceVisitor {
 pub struct SynthImplVisitor {
     look_for: String,
     voice_name: Ident,
-    len: usize,
+    parameters_name: Ident,
+    count: TokenStream,
+    sizes: SynthSizes,
+    // Whether the impl SynthDevice and impl Voice were there to fill in
+    pub found_device: bool,
+    pub found_voice: bool,
 }
 
 impl SynthImplVisitor {
-    pub fn new(look_for: String, voice_name: Ident, len: usize) -> SynthImplVisitor {
+    pub fn new(
+        look_for: String,
+        voice_name: Ident,
+        parameters_name: Ident,
+        count: TokenStream,
+        sizes: SynthSizes,
+    ) -> SynthImplVisitor {
         SynthImplVisitor {
             look_for,
             voice_name,
-            len,
+            parameters_name,
+            count,
+            sizes,
+            found_device: false,
+            found_voice: false,
         }
     }
 }
@@ -250,10 +288,22 @@ impl VisitMut for SynthImplVisitor {
                     .map(|s| &s.ident)
                     .collect::<Vec<_>>();
                 if path == [self.look_for.clone()] && path2 == ["SynthDevice"] {
+                    self.found_device = true;
                     let voice_name = self.voice_name.clone();
-                    let len = self.len;
+                    let count = &self.count;
                     node.items.push(ImplItem::Verbatim(quote! {
                     type Voice = #voice_name;}));
+                    let SynthSizes {
+                        voices,
+                        events,
+                        active_notes,
+                    } = self.sizes;
+                    node.items.push(ImplItem::Verbatim(quote! {
+                    const MAX_VOICES: usize = #voices;}));
+                    node.items.push(ImplItem::Verbatim(quote! {
+                    const MAX_EVENTS: usize = #events;}));
+                    node.items.push(ImplItem::Verbatim(quote! {
+                    const MAX_ACTIVE_NOTES: usize = #active_notes;}));
 
                     variable!(
                         node,
@@ -301,11 +351,60 @@ impl VisitMut for SynthImplVisitor {
                         }
                     }));
 
+                    node.items.push(ImplItem::Verbatim(quote! {
+                    fn get_tuning(&self) -> &'static Tuning
+                        {
+                            self.tuning
+                        }
+                    }));
+                    node.items.push(ImplItem::Verbatim(quote! {
+                    fn set_tuning(&mut self, n: &'static Tuning)
+                        {
+                            self.tuning = n;
+                            for voice in self.voices.iter_mut() {
+                                voice.set_tuning(self.tuning);
+                            }
+                        }
+                    }));
+
+                    node.items.push(ImplItem::Verbatim(quote! {
+                    fn seed(&mut self, seed: u32)
+                        {
+                            let rng = Rng::new(seed);
+                            for (i, voice) in self.voices.iter_mut().enumerate() {
+                                voice.set_rng(rng.fork(i as u32));
+                            }
+                        }
+                    }));
+
+                    variable!(node, mod_wheel, get_mod_wheel, set_mod_wheel, Sample);
+                    let parameters_name = self.parameters_name.clone();
+                    node.items.push(ImplItem::Verbatim(quote! {
+                        fn get_mod_matrix(&self) -> &ModMatrix<#parameters_name> {
+                            &self.mod_matrix
+                        }
+                    }));
+                    node.items.push(ImplItem::Verbatim(quote! {
+                        fn get_mod_matrix_mut(&mut self) -> &mut ModMatrix<#parameters_name> {
+                            &mut self.mod_matrix
+                        }
+                    }));
+                    node.items.push(ImplItem::Verbatim(quote! {
+                        fn get_mod_lfos_mut(&mut self) -> &mut [Lfo; 2] {
+                            &mut self.mod_lfos
+                        }
+                    }));
+                    node.items.push(ImplItem::Verbatim(quote! {
+                        fn get_modulated_param<T: Parameter>(&self, ty: Self::Param, sources: &ModSources) -> T {
+                            ty.convert(self.mod_matrix.modulated(&self._chunk_data, ty, sources))
+                        }
+                    }));
                     node.items.push(ImplItem::Verbatim(quote! {
                         fn all_notes_off(&mut self) {
+                            let params = ParamView::new(&self._chunk_data);
                             for voice in self.voices.iter_mut() {
                                 if voice.is_on() {
-                                    voice.note_off();
+                                    voice.note_off(params);
                                 }
                             }
                             self.mono_active = false;
@@ -318,34 +417,38 @@ impl VisitMut for SynthImplVisitor {
                     }));
                     node.items.push(ImplItem::Verbatim(quote! {
                         fn clear_events(&mut self) {
-                            for event in self.events.iter_mut() {
-                                event.ty = EventType::None;
+                            self.events.clear();
+                        }
+                    }));
+                    node.items.push(ImplItem::Verbatim(quote! {
+                        fn take_dropped_events(&mut self) -> Result<(), DeviceError> {
+                            match self.events.take_dropped() {
+                                0 => Ok(()),
+                                dropped => Err(DeviceError::new(
+                                    Self::NAME,
+                                    DeviceErrorKind::EventOverflow { dropped },
+                                )),
                             }
                         }
                     }));
                     node.items.push(ImplItem::Verbatim(quote! {
                         fn note_on(&mut self, note: Note, velocity: u32, delta_samples: usize) {
-                            for event in self.events.iter_mut() {
-                                if event.ty == EventType::None {
-                                    event.ty = EventType::NoteOn;
-                                    event.delta_samples = delta_samples;
-                                    event.note = note;
-                                    event.velocity = velocity;
-                                    break;
-                                }
-                            }
+                            self.events.push(Event {
+                                ty: EventType::NoteOn,
+                                delta_samples,
+                                note,
+                                velocity,
+                            });
                         }
                     }));
                     node.items.push(ImplItem::Verbatim(quote! {
                         fn note_off(&mut self, note: Note, delta_samples: usize) {
-                            for event in self.events.iter_mut() {
-                                if event.ty == EventType::None {
-                                    event.ty = EventType::NoteOff;
-                                    event.delta_samples = delta_samples;
-                                    event.note = note;
-                                    break;
-                                }
-                            }
+                            self.events.push(Event {
+                                ty: EventType::NoteOff,
+                                delta_samples,
+                                note,
+                                velocity: 0,
+                            });
                         }
                     }));
 
@@ -364,35 +467,45 @@ impl VisitMut for SynthImplVisitor {
                                 spans: [Span::call_site()],
                             },
                             args: Punctuated::from_iter(once(GenericArgument::Const(
-                                syn::Expr::Lit(ExprLit {
-                                    attrs: vec![],
-                                    lit: syn::Lit::Int(LitInt::new(
-                                        &len.to_string(),
-                                        Span::call_site(),
-                                    )),
-                                }),
+                                parse_quote!({ #count }),
                             ))),
                             gt_token: Gt {
                                 spans: [Span::call_site()],
                             },
                         });
                 } else if path == [self.voice_name.to_string()] && path2 == ["Voice"] {
+                    self.found_voice = true;
+                    let parameters_name = &self.parameters_name;
                     node.items.push(ImplItem::Verbatim(quote! {
-                        fn note_on(&mut self, note: Note, velocity: u32, detune: Detune, pan: Pan) {
+                    type Param = #parameters_name;}));
+                    node.items.push(ImplItem::Verbatim(quote! {
+                        fn note_on(&mut self, params: ParamView<Self::Param>, note: Note, velocity: u32, detune: Detune, pan: Pan) {
                             self.is_on = true;
                             self.note = note;
                             self.current_note = note;
                             self.detune = detune;
                             self.pan = pan;
+                            self.velocity = velocity;
                             self.slide_active = false;
+                            self.on_note_on(params, velocity);
                         }
                     }));
                     node.items.push(ImplItem::Verbatim(quote! {
                         fn note_slide(&mut self, note: Note) {
-                            self.slide_active = true;
-                            self.destination_note = note;
-                            self.slide_delta = ((*note-*self.current_note).molive_div(*self.slide_time)).into();
-                            self.slide_samples = self.slide_time.to_num();
+                            match (*note - *self.current_note).checked_molive_div(*self.slide_time) {
+                                Ok(delta) => {
+                                    self.slide_active = true;
+                                    self.destination_note = note;
+                                    self.slide_delta = delta;
+                                    self.slide_samples = self.slide_time.to_num();
+                                }
+                                // No slide time, so jump straight to the note
+                                Err(_) => {
+                                    self.slide_active = false;
+                                    self.note = note;
+                                    self.current_note = note;
+                                }
+                            }
                         }
                     }));
 
@@ -411,6 +524,13 @@ impl VisitMut for SynthImplVisitor {
                         }
                     }));
 
+                    node.items.push(ImplItem::Verbatim(quote! {
+                        fn get_freq(&mut self) -> Freq {
+                            let note = self.get_note();
+                            self.tuning.freq(note)
+                        }
+                    }));
+
                     variable!(node, detune, get_detune, set_detune, Detune);
                     variable!(node, pan, get_pan, set_pan, Pan);
                     variable!(
@@ -431,6 +551,12 @@ impl VisitMut for SynthImplVisitor {
                             self.slide_time = n;
                         }
                     }));
+                    node.items.push(ImplItem::Verbatim(quote! {
+                        fn set_tuning(&mut self, n: &'static Tuning) {
+                            self.tuning = n;
+                        }
+                    }));
+                    variable!(node, rng, get_rng, set_rng, Rng);
                 }
             }
         }
@@ -443,62 +569,70 @@ pub fn get_run() -> TokenStream {
     {
         let mut num_samples = buffer.len();
         let mut position = 0;
+        let params = ParamView::new(&self._chunk_data);
 
         while num_samples > 0
         {
-            let mut samples_to_next_event = num_samples;
-            for e in self.events.iter_mut()
+            while let Some(e) = self.events.pop_due()
             {
-                if e.ty != EventType::None
+                match e.ty
                 {
-                    if e.delta_samples == 0
+                    EventType::NoteOn =>
                     {
-                        match e.ty
+                        let mut j = *self.voices_unisono;
+                        for voice in self.voices.iter_mut()
                         {
-                            EventType::NoteOn =>
-                            {
-                                let mut j = *self.voices_unisono;
-                                for voice in self.voices.iter_mut()
-                                {
-                                    if !voice.is_on()
-                                    {
-                                        j-=1;
-                                        let f = Sample::from_num(j) / (if *self.voices_unisono > 1 { *self.voices_unisono - 1 } else {1});
-                                        voice.note_on(e.note, e.velocity, (f * *self.voices_detune).into(), ((f - sf!(0.5)) * (*self.voices_pan * s!(2) - s!(1)) + sf!(0.5)).into());
-                                    }
-                                    if j < 1 {break;}
-                                }
-                                e.ty = EventType::None;
-                            },
-                            EventType::NoteOff =>
+                            if !voice.is_on()
                             {
-                                for voice in self.voices.iter_mut()
-                                {
-                                    if voice.is_on() && voice.note == e.note {voice.note_off();}
-                                }
-                                e.ty = EventType::None;
-                            },
-                            EventType::None => {},
+                                j-=1;
+                                let f = Sample::from_num(j) / (if *self.voices_unisono > 1 { *self.voices_unisono - 1 } else {1});
+                                voice.note_on(params, e.note, e.velocity, (f * *self.voices_detune).into(), ((f - sf!(0.5)) * (*self.voices_pan * s!(2) - s!(1)) + sf!(0.5)).into());
+                            }
+                            if j < 1 {break;}
                         }
-                    }
-                    else if e.delta_samples < samples_to_next_event
+                    },
+                    EventType::NoteOff =>
                     {
-                        samples_to_next_event = e.delta_samples;
-                    }
+                        for voice in self.voices.iter_mut()
+                        {
+                            if voice.is_on() && voice.note == e.note {voice.note_off(params);}
+                        }
+                    },
+                    EventType::None => {},
                 }
             }
+            let samples_to_next_event = self.events.next_delta().map_or(num_samples, |d| d.min(num_samples));
 
             let output = &mut buffer[position..(samples_to_next_event+position)];
 
-            for voice in self.voices.iter_mut()
+            let modulated = !self.mod_matrix.is_empty();
+            for (i, voice) in self.voices.iter_mut().enumerate()
             {
-                if voice.is_on() {voice.run(song_position, output)?;}
+                if !voice.is_on() {continue;}
+                // The matrix is applied once per block, from where each voice's sources are at
+                // the start of it
+                let mut chunk = self._chunk_data;
+                if modulated
+                {
+                    let mut sources = ModSources {
+                        lfo: [self.mod_lfos[0].value(), self.mod_lfos[1].value()],
+                        envelope: voice.mod_envelopes(),
+                        mod_wheel: self.mod_wheel,
+                        ..Default::default()
+                    };
+                    sources.set_velocity(voice.velocity);
+                    sources.set_note(voice.note);
+                    self.mod_matrix.apply(&self._chunk_data, &mut chunk, &sources);
+                }
+                voice.run(ParamView::new(&chunk), song_position, output).map_err(|e| e.in_voice(Self::NAME, i))?;
+                if voice.is_finished() {voice.is_on = false;}
             }
-            for event in self.events.iter_mut()
+            for lfo in self.mod_lfos.iter_mut()
             {
-                if event.ty != EventType::None {event.delta_samples -= samples_to_next_event;}
+                lfo.advance(samples_to_next_event);
             }
-            song_position += samples_to_next_event / (crate::cst::SAMPLE_RATE as usize);
+            self.events.advance(samples_to_next_event);
+            song_position += samples_to_next_event;
             position += samples_to_next_event;
             num_samples -= samples_to_next_event;
         }
@@ -11,7 +11,10 @@ use cortex_m_rt::{entry, exception};
 use defmt::*;
 use defmt_rtt as _;
 use panic_probe as _;
-use pico_donk_core::{cst::Sample, Song};
+use pico_donk_core::{
+    cst::{sample_rate, set_sample_rate, Sample},
+    Song,
+};
 use rp2040_hal as hal;
 
 use hal::{clocks::init_clocks_and_plls, pac, watchdog::Watchdog};
@@ -48,6 +51,8 @@ fn main() -> ! {
     .ok()
     .unwrap();
 
+    // Lower rates leave more time per sample for synthesis
+    set_sample_rate(48000);
     let mut song = Song::new();
 
     // We're performing extremely low level stuff here because then I know it's working
@@ -88,7 +93,8 @@ fn main() -> ! {
 
     // Set up the systick registers
     let tenms = cortex_m::peripheral::SYST::get_ticks_per_10ms();
-    core.SYST.set_reload(tenms / 240); //48000hz
+    // SysTick fires every reload + 1 ticks, so one interrupt per sample
+    core.SYST.set_reload(tenms * 100 / sample_rate() - 1);
 
     core.SYST.clear_current();
     core.SYST.enable_interrupt();